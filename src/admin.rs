@@ -0,0 +1,210 @@
+//! Admin endpoint shared by the brokers.
+//!
+//! A broker started with `--admin <addr>` binds a REP socket there and
+//! answers the following commands, one `key=value` pair per line:
+//! - `stats`: uptime, and messages and bytes forwarded in each direction;
+//! - `peers`: identities of the clients and workers seen since the broker
+//!   started (the sockets only report the disconnections of a few peers);
+//! - `queues`: current backlog of each of the broker queues;
+//! - `reset`: sets the counters back to zero.
+//!
+//! The `broker_stat` example polls this endpoint and shows it as a table.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::{pending, poll_fn},
+    net::SocketAddr,
+    pin::Pin,
+    task::Poll,
+};
+
+use futures_core::Stream;
+use tokio::time::Instant;
+use zeromq::{SocketEvent, ZmqMessage, prelude::*};
+
+use crate::capture::Direction;
+
+/// Counters kept by a broker for its admin endpoint.
+pub struct Stats {
+    since: Instant,
+    messages: BTreeMap<&'static str, u64>,
+    bytes: BTreeMap<&'static str, u64>,
+    /// Peers seen so far, by role ("client", "worker", ...).
+    peers: BTreeMap<&'static str, BTreeSet<String>>,
+    /// Current length of each queue.
+    queues: BTreeMap<&'static str, usize>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            messages: BTreeMap::new(),
+            bytes: BTreeMap::new(),
+            peers: BTreeMap::new(),
+            queues: BTreeMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts a forwarded message.
+    pub fn record(&mut self, direction: Direction, message: &ZmqMessage) {
+        let bytes: usize = message.iter().map(|f| f.len()).sum();
+        *self.messages.entry(direction.as_str()).or_default() += 1;
+        *self.bytes.entry(direction.as_str()).or_default() += bytes as u64;
+    }
+
+    /// Remembers a peer.
+    pub fn peer(&mut self, role: &'static str, identity: &[u8]) {
+        self.peers.entry(role).or_default().insert(hex(identity));
+    }
+
+    /// Learns about a peer that connected, or disconnected, from an event
+    /// of the monitor of its socket.
+    pub fn event(&mut self, role: &'static str, event: SocketEvent) {
+        match event {
+            SocketEvent::Accepted(_, id) | SocketEvent::Connected(_, id) => self.peer(role, &id),
+            SocketEvent::Disconnected(id) => {
+                if let Some(peers) = self.peers.get_mut(role) {
+                    peers.remove(&hex(&id));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Updates the length of a queue.
+    pub fn queue(&mut self, name: &'static str, len: usize) {
+        self.queues.insert(name, len);
+    }
+
+    fn answer(&mut self, command: &str) -> String {
+        let mut out = String::new();
+        match command {
+            "stats" => {
+                out.push_str(&format!("uptime_s={}\n", self.since.elapsed().as_secs()));
+                for (direction, count) in &self.messages {
+                    out.push_str(&format!("messages.{direction}={count}\n"));
+                }
+                for (direction, count) in &self.bytes {
+                    out.push_str(&format!("bytes.{direction}={count}\n"));
+                }
+                let backlog: usize = self.queues.values().sum();
+                out.push_str(&format!("backlog={backlog}\n"));
+            }
+            "peers" => {
+                for (role, ids) in &self.peers {
+                    for id in ids {
+                        out.push_str(&format!("{role}={id}\n"));
+                    }
+                }
+            }
+            "queues" => {
+                for (name, len) in &self.queues {
+                    out.push_str(&format!("{name}={len}\n"));
+                }
+            }
+            "reset" => {
+                self.since = Instant::now();
+                self.messages.clear();
+                self.bytes.clear();
+                out.push_str("ok=true\n");
+            }
+            other => out.push_str(&format!("error=unknown command '{other}'\n")),
+        }
+        out
+    }
+}
+
+fn hex(identity: &[u8]) -> String {
+    identity.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Events of a socket monitor, as returned by `Socket::monitor`.
+pub type Monitor = futures_channel::mpsc::Receiver<SocketEvent>;
+
+/// Monitors of the sockets of a broker, by the role of their peers.
+/// zeromq drops the events that do not fit in the bounded channel of a
+/// monitor, so brokers wait on `next` in their loop to drain them as they come.
+pub struct Monitors {
+    /// Whether the broker has an admin endpoint; the events are of no use otherwise.
+    enabled: bool,
+    monitors: Vec<(&'static str, Monitor)>,
+}
+
+impl Monitors {
+    pub fn new(admin_addr: Option<SocketAddr>) -> Self {
+        Self {
+            enabled: admin_addr.is_some(),
+            monitors: Vec::new(),
+        }
+    }
+
+    /// Monitors `sock`, whose peers have the `role`.
+    pub fn watch(&mut self, role: &'static str, sock: &mut impl Socket) {
+        if self.enabled {
+            self.monitors.push((role, sock.monitor()));
+        }
+    }
+
+    /// Waits for the next event of any monitor; never returns if there is none.
+    pub async fn next(&mut self) -> (&'static str, SocketEvent) {
+        poll_fn(|cx| {
+            let mut i = 0;
+            while i < self.monitors.len() {
+                let (role, monitor) = &mut self.monitors[i];
+                match Pin::new(monitor).poll_next(cx) {
+                    Poll::Ready(Some(event)) => return Poll::Ready((*role, event)),
+                    // The socket is gone
+                    Poll::Ready(None) => _ = self.monitors.remove(i),
+                    Poll::Pending => i += 1,
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// REP socket answering admin commands.
+pub struct Admin {
+    sock: zeromq::RepSocket,
+}
+
+impl Admin {
+    /// Binds the admin socket to `addr`, or returns `None` if no address was given.
+    pub async fn bind(addr: Option<SocketAddr>) -> anyhow::Result<Option<Self>> {
+        let Some(addr) = addr else {
+            return Ok(None);
+        };
+        let mut sock = zeromq::RepSocket::new();
+        sock.bind(format!("tcp://{addr}").as_str()).await?;
+        println!("Admin endpoint on {addr}");
+        Ok(Some(Self { sock }))
+    }
+}
+
+/// Waits for the next admin command; never returns if there is no admin socket.
+pub async fn recv(admin: &mut Option<Admin>) -> anyhow::Result<String> {
+    match admin {
+        Some(admin) => {
+            let msg = admin.sock.recv().await?;
+            Ok(String::try_from(msg).unwrap_or_default())
+        }
+        None => pending().await,
+    }
+}
+
+/// Answers an admin command received with `recv`.
+pub async fn answer(
+    admin: &mut Option<Admin>,
+    stats: &mut Stats,
+    command: &str,
+) -> anyhow::Result<()> {
+    if let Some(admin) = admin {
+        let reply = stats.answer(command.trim());
+        admin.sock.send(reply.into()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, io::Write, net::SocketAddr, time::Duration};
+
+use clap::{CommandFactory, Parser};
+use tokio::time::{Instant, sleep};
+use zeromq::prelude::*;
+
+/// Polls the admin endpoint of a broker (see `--admin` on the brokers) and
+/// shows its counters as a live-updating table.
+#[derive(clap::Parser)]
+struct Cli {
+    /// Admin addr of the broker.
+    addr: SocketAddr,
+    /// Milliseconds between two polls.
+    #[arg(long, default_value_t = 1000)]
+    interval: u64,
+    /// Reset the broker counters and exit.
+    #[arg(long)]
+    reset: bool,
+}
+
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
+pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async { main_impl(args).await })
+}
+
+pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(args);
+
+    let mut sock = zeromq::ReqSocket::new();
+    sock.connect(format!("tcp://{}", cli.addr).as_str()).await?;
+    if cli.reset {
+        query(&mut sock, "reset").await?;
+        println!("Counters reset");
+        return Ok(());
+    }
+
+    let mut previous: Option<(Instant, BTreeMap<String, String>)> = None;
+    loop {
+        let stats: BTreeMap<_, _> = query(&mut sock, "stats").await?.into_iter().collect();
+        let queues = query(&mut sock, "queues").await?;
+        let peers = query(&mut sock, "peers").await?;
+        let now = Instant::now();
+
+        let mut out = String::from("\x1b[2J\x1b[H"); // clear the screen
+        out.push_str(&format!("Broker {}\n\n", cli.addr));
+        out.push_str(&format!(
+            "{:<32} {:>14} {:>12}\n",
+            "COUNTER", "VALUE", "PER SEC"
+        ));
+        for (key, value) in &stats {
+            let rate = previous
+                .as_ref()
+                .and_then(|(then, old)| {
+                    let (new, old) = (
+                        value.parse::<f64>().ok()?,
+                        old.get(key)?.parse::<f64>().ok()?,
+                    );
+                    // A counter that went down was reset, and counts from zero since
+                    let delta = if new < old { new } else { new - old };
+                    Some(delta / (now - *then).as_secs_f64())
+                })
+                .filter(|_| key.starts_with("messages.") || key.starts_with("bytes."))
+                .map(|rate| format!("{rate:.1}"))
+                .unwrap_or_default();
+            out.push_str(&format!("{key:<32} {value:>14} {rate:>12}\n"));
+        }
+        out.push_str(&format!("\n{:<32} {:>14}\n", "QUEUE", "LENGTH"));
+        for (name, len) in &queues {
+            out.push_str(&format!("{name:<32} {len:>14}\n"));
+        }
+        out.push_str(&format!("\n{:<32} {}\n", "SEEN PEER", "IDENTITY"));
+        for (role, identity) in &peers {
+            out.push_str(&format!("{role:<32} {identity}\n"));
+        }
+        std::io::stdout().write_all(out.as_bytes())?;
+        std::io::stdout().flush()?;
+
+        previous = Some((now, stats));
+        sleep(Duration::from_millis(cli.interval)).await;
+    }
+}
+
+/// Sends an admin command and parses its `key=value` lines.
+/// Peers may repeat the same key, so they are returned as a list.
+async fn query(
+    sock: &mut zeromq::ReqSocket,
+    command: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+    sock.send(command.into()).await?;
+    let reply = String::try_from(sock.recv().await?).map_err(|e| anyhow::anyhow!(e))?;
+    Ok(reply
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
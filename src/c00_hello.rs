@@ -1,16 +1,27 @@
 use std::{net::SocketAddr, time::Duration};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use tokio::time::sleep;
 use zeromq::{ZmqMessage, prelude::*};
 
+mod freelance;
+
 /// Types of behaviour for this program: a server, a client, ...
 #[derive(Debug, clap::Subcommand)]
 enum Mode {
     /// Run the server, specifying the bind addr.
     Server { addr: SocketAddr },
     /// Run the client, specifying the remote addr.
-    Client { addr: SocketAddr },
+    Client {
+        #[arg(required_unless_present = "servers")]
+        addr: Option<SocketAddr>,
+        /// Talk to these servers directly instead (Freelance pattern).
+        #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+        servers: Vec<SocketAddr>,
+        /// How to pick the servers when `--servers` is given.
+        #[arg(long, value_enum, default_value_t)]
+        strategy: freelance::Strategy,
+    },
 }
 
 /// Used with `clap` crate to handle the CLI arguments
@@ -20,7 +31,14 @@ struct Cli {
     cmd: Mode,
 }
 
-const SERVER_REPLY: &'static str = "World";
+const SERVER_REPLY: &str = "World";
+/// Health checks from Freelance clients, answered right away.
+const PING: &str = "PING";
+
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
 
 /// Sync function that calls the async main function.
 /// Needed because the `pluribus` crate cannot call async functions.
@@ -34,11 +52,16 @@ pub fn main<'a>(args: impl IntoIterator<Item = &'a String>) -> anyhow::Result<()
 /// Entry point of this program.
 /// Based on the CLI arguments, call the server or client handlers.
 pub async fn main_impl(args: impl IntoIterator<Item = &String>) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
         Mode::Server { addr } => server_handler(addr).await,
-        Mode::Client { addr } => client_handler(addr).await,
+        Mode::Client {
+            addr: Some(addr), ..
+        } => client_handler(addr).await,
+        Mode::Client {
+            servers, strategy, ..
+        } => freelance::client_handler(servers, strategy).await,
     }
 }
 
@@ -52,10 +75,14 @@ async fn server_handler(bind_addr: SocketAddr) -> anyhow::Result<()> {
     loop {
         match sock.recv().await {
             Ok(msg) => {
-                if msg.len() == 0 {
+                if msg.is_empty() {
                     println!("ERROR: Msg empty!");
                     continue;
                 }
+                if msg.get(0).is_some_and(|body| body == PING) {
+                    sock.send(ZmqMessage::from(PING)).await?;
+                    continue;
+                }
                 println!("Received Hello");
 
                 sleep(Duration::from_secs(1)).await;
@@ -0,0 +1,240 @@
+//! Brokerless reliable client for the `c00_hello` example (the zguide
+//! "Freelance" patterns).
+//!
+//! The client talks directly to several servers, each through its own
+//! DEALER socket, so it always knows which server answered. Requests carry a
+//! sequence number in their envelope (`[seq, "", "Hello"]`), which the REP
+//! servers echo back untouched, so late replies to old requests are ignored.
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    sync::mpsc,
+    time::{Instant, timeout_at},
+};
+use zeromq::{ZmqMessage, prelude::*};
+
+/// Time the client waits for a reply before trying elsewhere.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(2500);
+/// Time between two pings to the same server (tracked strategy).
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Time after which a server that answered nothing is considered dead.
+const SERVER_TTL: Duration = Duration::from_secs(3);
+/// Envelope and body of pings; servers answer them without the usual delay.
+const PING: &str = "PING";
+
+/// How the client picks the servers to send a request to.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum Strategy {
+    /// Try the servers one after the other until one answers.
+    #[default]
+    Sequential,
+    /// Send to all servers at once and take the first reply.
+    Shotgun,
+    /// Ping the servers and only send requests to those known to be alive.
+    Tracked,
+}
+
+/// Connections to all the servers.
+/// Every server gets a task owning its socket, so a server that is down
+/// (where connecting blocks) does not hold back the others.
+struct Servers {
+    addrs: Vec<SocketAddr>,
+    outboxes: Vec<mpsc::UnboundedSender<ZmqMessage>>,
+    replies: mpsc::UnboundedReceiver<(usize, Vec<Bytes>)>,
+}
+
+impl Servers {
+    fn connect(addrs: Vec<SocketAddr>) -> Self {
+        let (reply_tx, replies) = mpsc::unbounded_channel();
+        let outboxes = addrs
+            .iter()
+            .enumerate()
+            .map(|(index, addr)| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(server_link(*addr, index, rx, reply_tx.clone()));
+                tx
+            })
+            .collect();
+        Self {
+            addrs,
+            outboxes,
+            replies,
+        }
+    }
+
+    /// Queues a message for a server, with `tag` as its envelope.
+    fn send(&self, server: usize, tag: &str, body: &'static str) {
+        let msg = vec![
+            Bytes::from(tag.to_string()),
+            Bytes::new(),
+            Bytes::from_static(body.as_bytes()),
+        ];
+        let msg = ZmqMessage::try_from(msg).expect("message has frames");
+        let _ = self.outboxes[server].send(msg);
+    }
+
+    /// Waits for the next reply (server index and envelope tag), or `None`
+    /// once the deadline is reached.
+    async fn recv(&mut self, deadline: Instant) -> Option<(usize, String)> {
+        match timeout_at(deadline, self.replies.recv()).await {
+            Ok(Some((server, frames))) => {
+                let tag = frames
+                    .first()
+                    .map(|t| String::from_utf8_lossy(t).to_string());
+                Some((server, tag.unwrap_or_default()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Owns the DEALER socket of a server: sends what is queued for it and
+/// hands every reply back to the client.
+async fn server_link(
+    addr: SocketAddr,
+    index: usize,
+    mut outbox: mpsc::UnboundedReceiver<ZmqMessage>,
+    replies: mpsc::UnboundedSender<(usize, Vec<Bytes>)>,
+) -> anyhow::Result<()> {
+    let mut sock = zeromq::DealerSocket::new();
+    // Nobody awaits this task, so report why the server is unreachable here
+    sock.connect(format!("tcp://{addr}").as_str())
+        .await
+        .inspect_err(|e| eprintln!("Error connecting to {addr}: {e}"))?;
+    loop {
+        tokio::select! {
+            msg = outbox.recv() => match msg {
+                Some(msg) => if let Err(e) = sock.send(msg).await {
+                    eprintln!("Error sending to {addr}: {e}");
+                },
+                None => return Ok(()),
+            },
+            msg = sock.recv() => match msg {
+                Ok(msg) => replies.send((index, msg.into_vec()))?,
+                Err(e) => eprintln!("Error receiving from {addr}: {e}"),
+            },
+        }
+    }
+}
+
+/// Client code for the brokerless mode.
+/// Sends "Hello" 10 times using the chosen strategy and reports which
+/// server answered each request.
+pub async fn client_handler(addrs: Vec<SocketAddr>, strategy: Strategy) -> anyhow::Result<()> {
+    println!("Connecting to {} servers ({strategy:?})...", addrs.len());
+    let mut servers = Servers::connect(addrs);
+    let mut health = Health::new(servers.addrs.len());
+
+    for i in 0..10 {
+        println!("Sending Hello {i}...");
+        let seq = i.to_string();
+        let answer = match strategy {
+            Strategy::Sequential => sequential(&mut servers, &seq).await,
+            Strategy::Shotgun => shotgun(&mut servers, &seq).await,
+            Strategy::Tracked => tracked(&mut servers, &mut health, &seq).await,
+        };
+        match answer {
+            Some(server) => println!("Received World {i} from {}", servers.addrs[server]),
+            None => eprintln!("ERROR: No server answered Hello {i}"),
+        }
+    }
+    Ok(())
+}
+
+/// Sequential failover: one server at a time, in order.
+async fn sequential(servers: &mut Servers, seq: &str) -> Option<usize> {
+    for server in 0..servers.addrs.len() {
+        servers.send(server, seq, "Hello");
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        while let Some((from, tag)) = servers.recv(deadline).await {
+            if tag == seq {
+                return Some(from);
+            }
+        }
+        eprintln!("No reply from {}", servers.addrs[server]);
+    }
+    None
+}
+
+/// Shotgun: every server gets the request, the fastest one wins.
+async fn shotgun(servers: &mut Servers, seq: &str) -> Option<usize> {
+    for server in 0..servers.addrs.len() {
+        servers.send(server, seq, "Hello");
+    }
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    while let Some((from, tag)) = servers.recv(deadline).await {
+        if tag == seq {
+            return Some(from);
+        }
+    }
+    None
+}
+
+/// What the client knows about the health of each server.
+struct Health {
+    /// When each server last answered anything.
+    last_seen: Vec<Option<Instant>>,
+    /// When each server is due for its next ping.
+    ping_at: Vec<Instant>,
+}
+
+impl Health {
+    fn new(count: usize) -> Self {
+        Self {
+            last_seen: vec![None; count],
+            ping_at: vec![Instant::now(); count],
+        }
+    }
+
+    fn alive(&self, server: usize, now: Instant) -> bool {
+        self.last_seen[server].is_some_and(|seen| now - seen < SERVER_TTL)
+    }
+
+    /// Pings every server that is due for it.
+    fn ping(&mut self, servers: &Servers, now: Instant) {
+        for (server, ping_at) in self.ping_at.iter_mut().enumerate() {
+            if *ping_at <= now {
+                servers.send(server, PING, PING);
+                *ping_at = now + PING_INTERVAL;
+            }
+        }
+    }
+}
+
+/// Tracked servers: requests only go to the first server known to be alive,
+/// and every reply (pings included) keeps a server alive.
+async fn tracked(servers: &mut Servers, health: &mut Health, seq: &str) -> Option<usize> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT * servers.addrs.len() as u32;
+    let mut target: Option<(usize, Instant)> = None;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        health.ping(servers, now);
+
+        if let Some((server, sent)) = target
+            && (!health.alive(server, now) || now - sent >= REQUEST_TIMEOUT)
+        {
+            eprintln!("No reply from {}", servers.addrs[server]);
+            health.last_seen[server] = None;
+            target = None;
+        }
+        if target.is_none()
+            && let Some(server) = (0..servers.addrs.len()).find(|s| health.alive(*s, now))
+        {
+            servers.send(server, seq, "Hello");
+            target = Some((server, now));
+        }
+
+        let wake = (now + PING_INTERVAL / 4).min(deadline);
+        if let Some((from, tag)) = servers.recv(wake).await {
+            health.last_seen[from] = Some(Instant::now());
+            if tag == seq {
+                return Some(from);
+            }
+        }
+    }
+}
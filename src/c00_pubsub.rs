@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, time::Duration};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use rand::Rng;
 use tokio::{io::AsyncWriteExt, time::sleep};
 use zeromq::prelude::*;
@@ -19,6 +19,11 @@ struct Cli {
     cmd: Mode,
 }
 
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
 pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -27,7 +32,7 @@ pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<(
 }
 
 pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
         Mode::Publisher { addr } => pub_handler(addr).await,
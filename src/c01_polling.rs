@@ -1,7 +1,7 @@
 use std::{net::SocketAddr, str::FromStr, time::Duration};
 
 use anyhow::anyhow;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use rand::Rng;
 use tokio::{io::AsyncWriteExt, time::sleep};
 use zeromq::{ZmqMessage, prelude::*};
@@ -59,7 +59,7 @@ impl FromStr for ZipCode {
                 if bytes.len() > 7 {
                     return Err(anyhow!("Pt: too long zipcode"));
                 }
-                let code = u32::from_str_radix(String::from_utf8_lossy(&bytes[3..]).as_ref(), 10)?;
+                let code = String::from_utf8_lossy(&bytes[3..]).parse::<u32>()?;
                 Ok(Self {
                     country: Country::Pt,
                     zip: code,
@@ -69,7 +69,7 @@ impl FromStr for ZipCode {
                 if bytes.len() > 8 {
                     return Err(anyhow!("Us: too long zipcode"));
                 }
-                let code = u32::from_str_radix(String::from_utf8_lossy(&bytes[3..]).as_ref(), 10)?;
+                let code = String::from_utf8_lossy(&bytes[3..]).parse::<u32>()?;
                 Ok(Self {
                     country: Country::Us,
                     zip: code,
@@ -95,6 +95,11 @@ struct Cli {
     cmd: Mode,
 }
 
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
 pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -103,7 +108,7 @@ pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<(
 }
 
 pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
         Mode::Subscriber {
@@ -1,17 +1,93 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use clap::Parser;
+use bytes::Bytes;
+
+use clap::{CommandFactory, Parser};
 use tokio::time::sleep;
 use zeromq::{ZmqMessage, prelude::*};
 
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::{self, Direction, Tap},
+};
+
+mod bstar;
+mod peering;
+mod priority;
+mod titanic;
+
 #[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)] // parsed once, at startup
 enum Mode {
     /// Run the worker/server, specifying the bind addr.
-    Worker { addr: SocketAddr },
+    Worker {
+        addr: SocketAddr,
+        /// Also serve the backup broker of a Binary Star pair.
+        #[arg(long)]
+        backup: Option<SocketAddr>,
+        /// Tell the broker when this worker is idle (needed by federated and prioritized brokers).
+        #[arg(long, conflicts_with = "backup")]
+        ready: bool,
+    },
     /// Run the client, specifying the remote addr.
-    Client { addr: SocketAddr },
+    Client {
+        addr: SocketAddr,
+        /// Fail over to this Binary Star backup broker when `addr` is silent.
+        #[arg(long)]
+        backup: Option<SocketAddr>,
+        /// Priority class of the requests (needs a `--prioritize` broker).
+        #[arg(long, value_enum, conflicts_with = "backup")]
+        priority: Option<priority::Priority>,
+        /// Milliseconds each request may wait for its reply (needs a `--prioritize` broker).
+        #[arg(long, conflicts_with = "backup")]
+        deadline: Option<u64>,
+    },
     /// Run the broker, specifying the addresses of the client and the server.
-    Broker { client_addr: SocketAddr, worker_addr: SocketAddr },
+    Broker {
+        client_addr: SocketAddr,
+        worker_addr: SocketAddr,
+        /// Persist requests and replies in this directory (Titanic pattern).
+        #[arg(long, conflicts_with_all = ["primary", "backup", "name", "prioritize"])]
+        persist: Option<PathBuf>,
+        /// Republish every forwarded frame on a PUB socket bound here.
+        #[arg(long, conflicts_with_all = ["primary", "backup", "name", "prioritize"])]
+        capture: Option<SocketAddr>,
+        /// Answer admin commands (stats, peers, queues, reset) on a REP socket bound here.
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+        /// Run as the primary of a Binary Star pair.
+        #[arg(long, conflicts_with = "backup", requires_all = ["state_pub", "state_sub"])]
+        primary: bool,
+        /// Run as the backup of a Binary Star pair.
+        #[arg(long, requires_all = ["state_pub", "state_sub"])]
+        backup: bool,
+        /// Bind addr where this broker publishes its Binary Star state.
+        #[arg(long)]
+        state_pub: Option<SocketAddr>,
+        /// Addr where the peer broker publishes its Binary Star state.
+        #[arg(long)]
+        state_sub: Option<SocketAddr>,
+        /// Name of this broker in a federation.
+        #[arg(long, requires = "cloud")]
+        name: Option<String>,
+        /// Bind addr where peers send requests (state is published on the next port).
+        #[arg(long, requires = "name")]
+        cloud: Option<SocketAddr>,
+        /// Peer brokers, as NAME=cloud_addr.
+        #[arg(long, value_delimiter = ',', requires = "name")]
+        peers: Vec<peering::Peer>,
+        /// Serve requests by priority class and drop expired ones.
+        #[arg(long, conflicts_with = "name")]
+        prioritize: bool,
+    },
+    /// Inspect the traffic captured by a broker, specifying its capture addr.
+    Inspect { addr: SocketAddr },
+    /// Submit a request to a persistent broker and print its ID.
+    Submit { addr: SocketAddr, payload: String },
+    /// Ask a persistent broker for the reply of a request.
+    Poll { addr: SocketAddr, id: String },
+    /// Tell a persistent broker to forget a request and its reply.
+    Close { addr: SocketAddr, id: String },
 }
 
 #[derive(clap::Parser)]
@@ -20,7 +96,16 @@ struct Cli {
     cmd: Mode,
 }
 
-const SERVER_REPLY: &'static str = "World";
+const SERVER_REPLY: &str = "World";
+/// Message sent by `--ready` workers when they are free for a new request.
+const WORKER_READY: &str = "READY";
+/// Time a worker takes to answer a request.
+const WORK_TIME: Duration = Duration::from_secs(1);
+
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
 
 pub fn main<'a>(args: impl IntoIterator<Item = &'a String>) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -30,40 +115,136 @@ pub fn main<'a>(args: impl IntoIterator<Item = &'a String>) -> anyhow::Result<()
 }
 
 pub async fn main_impl(args: impl IntoIterator<Item = &String>) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
-        Mode::Worker { addr } => worker_handler(addr).await,
-        Mode::Client { addr } => client_handler(addr).await,
-        Mode::Broker { client_addr, worker_addr } => broker_handler(client_addr, worker_addr).await,
+        Mode::Worker {
+            addr, ready: true, ..
+        } => ready_worker_handler(addr).await,
+        Mode::Worker { addr, backup, .. } => worker_handler(addr, backup).await,
+        Mode::Client {
+            addr,
+            backup: Some(backup),
+            ..
+        } => bstar::client_handler(addr, backup).await,
+        Mode::Client {
+            addr,
+            priority: None,
+            deadline: None,
+            ..
+        } => client_handler(addr).await,
+        Mode::Client {
+            addr,
+            priority,
+            deadline,
+            ..
+        } => {
+            let budget = deadline.map(Duration::from_millis);
+            priority::client_handler(addr, priority.unwrap_or_default(), budget).await
+        }
+        Mode::Broker {
+            client_addr,
+            worker_addr,
+            persist,
+            capture,
+            admin,
+            primary,
+            backup,
+            state_pub,
+            state_sub,
+            name,
+            cloud,
+            peers,
+            prioritize,
+        } => match (persist, state_pub.zip(state_sub), name.zip(cloud)) {
+            (_, Some((state_pub, state_sub)), _) if primary || backup => {
+                bstar::broker_handler(
+                    client_addr,
+                    worker_addr,
+                    primary,
+                    state_pub,
+                    state_sub,
+                    admin,
+                )
+                .await
+            }
+            (_, _, Some((name, cloud))) => {
+                peering::broker_handler(client_addr, worker_addr, name, cloud, peers, admin).await
+            }
+            _ if prioritize => priority::broker_handler(client_addr, worker_addr, admin).await,
+            (Some(dir), _, _) => {
+                titanic::broker_handler(client_addr, worker_addr, dir, capture, admin).await
+            }
+            (None, _, _) => broker_handler(client_addr, worker_addr, capture, admin).await,
+        },
+        Mode::Submit { addr, payload } => titanic::command_handler(addr, "submit", payload).await,
+        Mode::Poll { addr, id } => titanic::command_handler(addr, "poll", id).await,
+        Mode::Close { addr, id } => titanic::command_handler(addr, "close", id).await,
+        Mode::Inspect { addr } => capture::inspect_handler(addr).await,
     }
 }
 
-async fn broker_handler(client_addr: SocketAddr, worker_addr: SocketAddr) -> anyhow::Result<()> {
+async fn broker_handler(
+    client_addr: SocketAddr,
+    worker_addr: SocketAddr,
+    capture_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut monitors = Monitors::new(admin_addr);
     let mut frontend = zeromq::RouterSocket::new();
+    monitors.watch("client", &mut frontend);
     frontend.bind(format!("tcp://{client_addr}").as_str()).await?;
     let mut backend = zeromq::DealerSocket::new();
+    monitors.watch("worker", &mut backend);
     backend.bind(format!("tcp://{worker_addr}").as_str()).await?;
-    
-    zeromq::proxy(frontend, backend, None).await?;
-    
-    Ok(())
+    let mut tap = Tap::bind(capture_addr).await?;
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+    let mut in_flight = 0usize;
+
+    // Same as `zeromq::proxy`, but the capture socket also learns the direction.
+    loop {
+        tokio::select! {
+            msg = frontend.recv() => {
+                let msg = msg?;
+                capture::capture(&mut tap, Direction::FrontendToBackend, &msg).await;
+                stats.record(Direction::FrontendToBackend, &msg);
+                in_flight += 1;
+                backend.send(msg).await?;
+            }
+            msg = backend.recv() => {
+                let msg = msg?;
+                capture::capture(&mut tap, Direction::BackendToFrontend, &msg).await;
+                stats.record(Direction::BackendToFrontend, &msg);
+                in_flight = in_flight.saturating_sub(1);
+                frontend.send(msg).await?;
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                stats.queue("in_flight", in_flight);
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
+    }
 }
 
-async fn worker_handler(addr: SocketAddr) -> anyhow::Result<()> {
+async fn worker_handler(addr: SocketAddr, backup: Option<SocketAddr>) -> anyhow::Result<()> {
     let mut sock = zeromq::RepSocket::new();
     sock.connect(format!("tcp://{addr}").as_str()).await?;
+    if let Some(backup) = backup {
+        sock.connect(format!("tcp://{backup}").as_str()).await?;
+    }
     
     loop {
         match sock.recv().await {
             Ok(msg) => {
-                if msg.len() == 0 {
+                if msg.is_empty() {
                     println!("ERROR: Msg empty!");
                     continue;
                 }
                 println!("Received Hello");
 
-                sleep(Duration::from_secs(1)).await;
+                sleep(WORK_TIME).await;
 
                 let reply = ZmqMessage::from(SERVER_REPLY);
                 sock.send(reply).await?;
@@ -73,6 +254,43 @@ async fn worker_handler(addr: SocketAddr) -> anyhow::Result<()> {
     }
 }
 
+/// Worker code for the brokers that need to know which workers are idle
+/// (federated and prioritized modes).
+/// The worker says `READY` once, and then every reply doubles as a sign that
+/// it is free again. Requests from a prioritized broker with a deadline carry
+/// the time left before the payload: if it is shorter than the work, the
+/// worker gives up right away.
+async fn ready_worker_handler(addr: SocketAddr) -> anyhow::Result<()> {
+    let mut sock = zeromq::ReqSocket::new();
+    sock.connect(format!("tcp://{addr}").as_str()).await?;
+    sock.send(ZmqMessage::from(WORKER_READY)).await?;
+
+    loop {
+        // [route..., "", (msec left,) request]
+        let mut msg = sock.recv().await?.into_vec();
+        let body = msg.iter().rposition(|f| f.is_empty()).map_or(0, |d| d + 1);
+        let budget = match msg.len() - body {
+            2 => String::from_utf8_lossy(&msg[body]).parse().ok().map(Duration::from_millis),
+            _ => None,
+        };
+        println!("Received Hello");
+
+        let reply = match budget {
+            Some(budget) if budget < WORK_TIME => {
+                println!("Only {budget:?} left, giving up");
+                priority::REPLY_EXPIRED
+            }
+            _ => {
+                sleep(WORK_TIME).await;
+                SERVER_REPLY
+            }
+        };
+        msg.truncate(body);
+        msg.push(Bytes::from_static(reply.as_bytes()));
+        sock.send(ZmqMessage::try_from(msg).expect("reply has frames")).await?;
+    }
+}
+
 async fn client_handler(connect_addr: SocketAddr) -> anyhow::Result<()> {
     println!("Connecting to hello world server...");
     let mut sock = zeromq::ReqSocket::new();
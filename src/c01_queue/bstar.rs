@@ -0,0 +1,448 @@
+//! Binary Star primary/backup pair for the `c01_queue` broker.
+//!
+//! Two brokers, one started with `--primary` and the other with `--backup`,
+//! publish their state to each other once per heartbeat. The finite-state
+//! machine below decides which of them is active: only the active broker
+//! serves clients, and the passive one takes over once the active broker
+//! has gone silent *and* clients start sending it requests. Requiring both
+//! conditions is what prevents a split brain when only the link between the
+//! two brokers is down.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::anyhow;
+use tokio::time::{Instant, interval, timeout};
+use zeromq::{ZmqMessage, prelude::*};
+
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::Direction,
+};
+
+/// Time between two state messages sent to the peer.
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+/// Time after which a silent peer is considered dead.
+const PEER_EXPIRY: Duration = Duration::from_secs(2);
+/// Time a client waits for a reply before failing over to the other broker.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(2500);
+
+/// States of the Binary Star finite-state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Primary, waiting for the peer to connect.
+    Primary,
+    /// Backup, waiting for the peer to connect.
+    Backup,
+    /// Serving client requests.
+    Active,
+    /// Standing by while the peer is active.
+    Passive,
+}
+
+/// Events fed to the finite-state machine. The first four are the states
+/// announced by the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    PeerPrimary,
+    PeerBackup,
+    PeerActive,
+    PeerPassive,
+    ClientRequest,
+}
+
+impl State {
+    /// The event the peer sees when we announce this state.
+    fn as_event(self) -> Event {
+        match self {
+            State::Primary => Event::PeerPrimary,
+            State::Backup => Event::PeerBackup,
+            State::Active => Event::PeerActive,
+            State::Passive => Event::PeerPassive,
+        }
+    }
+
+    fn to_wire(self) -> &'static str {
+        match self {
+            State::Primary => "PRIMARY",
+            State::Backup => "BACKUP",
+            State::Active => "ACTIVE",
+            State::Passive => "PASSIVE",
+        }
+    }
+
+    fn from_wire(s: &str) -> Option<Self> {
+        match s {
+            "PRIMARY" => Some(State::Primary),
+            "BACKUP" => Some(State::Backup),
+            "ACTIVE" => Some(State::Active),
+            "PASSIVE" => Some(State::Passive),
+            _ => None,
+        }
+    }
+}
+
+/// Why the finite-state machine refused an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// A client request arrived at a broker that must not serve it.
+    /// The request is dropped and the client will fail over.
+    NotActive,
+    /// Both brokers claim to be active or passive: the pair is broken.
+    Fatal(&'static str),
+}
+
+/// The Binary Star finite-state machine.
+#[derive(Debug)]
+pub struct Fsm {
+    pub state: State,
+    peer_expiry: Instant,
+}
+
+impl Fsm {
+    pub fn new(primary: bool, now: Instant) -> Self {
+        Self {
+            state: match primary {
+                true => State::Primary,
+                false => State::Backup,
+            },
+            peer_expiry: now + PEER_EXPIRY,
+        }
+    }
+
+    /// Records that the peer was heard from, then handles its state.
+    pub fn peer_state(&mut self, peer: State, now: Instant) -> Result<(), Refusal> {
+        self.peer_expiry = now + PEER_EXPIRY;
+        self.handle(peer.as_event(), now)
+    }
+
+    /// Applies an event, returning whether it was accepted.
+    pub fn handle(&mut self, event: Event, now: Instant) -> Result<(), Refusal> {
+        match (self.state, event) {
+            (State::Primary, Event::PeerBackup) => self.state = State::Active,
+            (State::Primary, Event::PeerActive) => self.state = State::Passive,
+            // A primary with no peer yet serves clients right away.
+            (State::Primary, Event::ClientRequest) => self.state = State::Active,
+            (State::Backup, Event::PeerActive) => self.state = State::Passive,
+            // A backup never serves clients until it has seen the primary.
+            (State::Backup, Event::ClientRequest) => return Err(Refusal::NotActive),
+            (State::Active, Event::PeerActive) => {
+                return Err(Refusal::Fatal("dual active brokers"));
+            }
+            // A restarted peer (primary or backup) joins as passive.
+            (State::Passive, Event::PeerPrimary | Event::PeerBackup) => self.state = State::Active,
+            (State::Passive, Event::PeerPassive) => {
+                return Err(Refusal::Fatal("dual passive brokers"));
+            }
+            // Fail over only when the peer is silent; otherwise the request
+            // probably reached us because of a network partition.
+            (State::Passive, Event::ClientRequest) => match now >= self.peer_expiry {
+                true => self.state = State::Active,
+                false => return Err(Refusal::NotActive),
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Broker code for the Binary Star mode.
+/// Works like the plain broker, but client requests are only forwarded to
+/// the workers while the finite-state machine says this broker is active.
+pub async fn broker_handler(
+    client_addr: SocketAddr,
+    worker_addr: SocketAddr,
+    primary: bool,
+    state_pub: SocketAddr,
+    state_sub: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut monitors = Monitors::new(admin_addr);
+    let mut frontend = zeromq::RouterSocket::new();
+    monitors.watch("client", &mut frontend);
+    frontend
+        .bind(format!("tcp://{client_addr}").as_str())
+        .await?;
+    let mut backend = zeromq::DealerSocket::new();
+    monitors.watch("worker", &mut backend);
+    backend
+        .bind(format!("tcp://{worker_addr}").as_str())
+        .await?;
+    let mut statepub = zeromq::PubSocket::new();
+    statepub.bind(format!("tcp://{state_pub}").as_str()).await?;
+    let mut statesub = zeromq::SubSocket::new();
+    statesub.subscribe("").await?;
+    statesub
+        .connect(format!("tcp://{state_sub}").as_str())
+        .await?;
+
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+    let mut in_flight = 0usize;
+
+    let mut fsm = Fsm::new(primary, Instant::now());
+    println!("Starting as {:?}", fsm.state);
+    let mut heartbeat = interval(HEARTBEAT);
+    loop {
+        let before = fsm.state;
+        tokio::select! {
+            msg = frontend.recv() => {
+                let msg = msg?;
+                match fsm.handle(Event::ClientRequest, Instant::now()) {
+                    Ok(()) => {
+                        stats.record(Direction::FrontendToBackend, &msg);
+                        in_flight += 1;
+                        backend.send(msg).await?
+                    }
+                    Err(Refusal::NotActive) => {} // the client will fail over
+                    Err(Refusal::Fatal(e)) => return Err(anyhow!("Binary Star failure: {e}")),
+                }
+            }
+            msg = backend.recv() => {
+                let msg = msg?;
+                stats.record(Direction::BackendToFrontend, &msg);
+                in_flight = in_flight.saturating_sub(1);
+                frontend.send(msg).await?
+            }
+            msg = statesub.recv() => {
+                let msg = String::try_from(msg?).map_err(|e| anyhow!(e))?;
+                let Some(peer) = State::from_wire(&msg) else {
+                    eprintln!("ERROR: Unknown peer state '{msg}'");
+                    continue;
+                };
+                if let Err(Refusal::Fatal(e)) = fsm.peer_state(peer, Instant::now()) {
+                    return Err(anyhow!("Binary Star failure: {e}"));
+                }
+            }
+            _ = heartbeat.tick() => {
+                statepub.send(fsm.state.to_wire().into()).await?;
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                stats.queue("in_flight", in_flight);
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
+        if fsm.state != before {
+            println!("{before:?} -> {:?}", fsm.state);
+        }
+    }
+}
+
+/// Client code for the Binary Star mode.
+/// Sends "Hello" 10 times; when a broker does not answer in time, the
+/// request is sent again through a fresh socket to the other broker.
+pub async fn client_handler(primary: SocketAddr, backup: SocketAddr) -> anyhow::Result<()> {
+    let servers = [primary, backup];
+    let mut current = 0;
+    let mut sock = None;
+
+    let mut i = 0;
+    while i < 10 {
+        println!("Sending Hello {i} to {}...", servers[current]);
+        // Connecting blocks while the broker is down, so it is part of the
+        // request as far as the timeout is concerned.
+        let reply = timeout(REQUEST_TIMEOUT, async {
+            let sock = match &mut sock {
+                Some(sock) => sock,
+                None => {
+                    let mut new = zeromq::ReqSocket::new();
+                    new.connect(format!("tcp://{}", servers[current]).as_str())
+                        .await?;
+                    sock.insert(new)
+                }
+            };
+            sock.send(ZmqMessage::from("Hello")).await?;
+            sock.recv().await
+        })
+        .await;
+        match reply {
+            Ok(Ok(_)) => {
+                println!("Received World {i} from {}", servers[current]);
+                i += 1;
+                continue;
+            }
+            Ok(Err(e)) => eprintln!("Error: {e}"),
+            Err(_) => eprintln!("No reply from {}", servers[current]),
+        }
+        current = (current + 1) % servers.len();
+        println!("Failing over to {}...", servers[current]);
+        // A REQ socket cannot send twice in a row, so start afresh.
+        sock = None;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fsm(state: State) -> (Fsm, Instant) {
+        let now = Instant::now();
+        let mut fsm = Fsm::new(true, now);
+        fsm.state = state;
+        (fsm, now)
+    }
+
+    #[test]
+    fn primary_goes_active_when_backup_connects() {
+        let (mut fsm, now) = fsm(State::Primary);
+        assert_eq!(fsm.peer_state(State::Backup, now), Ok(()));
+        assert_eq!(fsm.state, State::Active);
+    }
+
+    #[test]
+    fn primary_goes_passive_when_peer_is_active() {
+        let (mut fsm, now) = fsm(State::Primary);
+        assert_eq!(fsm.peer_state(State::Active, now), Ok(()));
+        assert_eq!(fsm.state, State::Passive);
+    }
+
+    #[test]
+    fn primary_serves_clients_without_peer() {
+        let (mut fsm, now) = fsm(State::Primary);
+        assert_eq!(fsm.handle(Event::ClientRequest, now), Ok(()));
+        assert_eq!(fsm.state, State::Active);
+    }
+
+    #[test]
+    fn backup_goes_passive_when_peer_is_active() {
+        let (mut fsm, now) = fsm(State::Backup);
+        assert_eq!(fsm.peer_state(State::Active, now), Ok(()));
+        assert_eq!(fsm.state, State::Passive);
+    }
+
+    #[test]
+    fn backup_refuses_clients_before_seeing_primary() {
+        let (mut fsm, now) = fsm(State::Backup);
+        assert_eq!(
+            fsm.handle(Event::ClientRequest, now + PEER_EXPIRY),
+            Err(Refusal::NotActive)
+        );
+        assert_eq!(fsm.state, State::Backup);
+    }
+
+    #[test]
+    fn backup_ignores_a_waiting_primary() {
+        let (mut fsm, now) = fsm(State::Backup);
+        assert_eq!(fsm.peer_state(State::Primary, now), Ok(()));
+        assert_eq!(fsm.state, State::Backup);
+    }
+
+    #[test]
+    fn active_keeps_serving() {
+        let (mut fsm, now) = fsm(State::Active);
+        assert_eq!(fsm.handle(Event::ClientRequest, now), Ok(()));
+        assert_eq!(fsm.peer_state(State::Passive, now), Ok(()));
+        assert_eq!(fsm.peer_state(State::Backup, now), Ok(()));
+        assert_eq!(fsm.peer_state(State::Primary, now), Ok(()));
+        assert_eq!(fsm.state, State::Active);
+    }
+
+    #[test]
+    fn dual_active_is_fatal() {
+        let (mut fsm, now) = fsm(State::Active);
+        assert!(matches!(
+            fsm.peer_state(State::Active, now),
+            Err(Refusal::Fatal(_))
+        ));
+    }
+
+    #[test]
+    fn passive_takes_over_from_restarted_peer() {
+        for peer in [State::Primary, State::Backup] {
+            let (mut fsm, now) = fsm(State::Passive);
+            assert_eq!(fsm.peer_state(peer, now), Ok(()));
+            assert_eq!(fsm.state, State::Active);
+        }
+    }
+
+    #[test]
+    fn dual_passive_is_fatal() {
+        let (mut fsm, now) = fsm(State::Passive);
+        assert!(matches!(
+            fsm.peer_state(State::Passive, now),
+            Err(Refusal::Fatal(_))
+        ));
+    }
+
+    #[test]
+    fn passive_stays_passive_while_peer_is_active() {
+        let (mut fsm, now) = fsm(State::Passive);
+        assert_eq!(fsm.peer_state(State::Active, now), Ok(()));
+        assert_eq!(fsm.state, State::Passive);
+    }
+
+    #[test]
+    fn passive_fails_over_once_peer_is_silent() {
+        let (mut fsm, now) = fsm(State::Passive);
+        assert_eq!(fsm.handle(Event::ClientRequest, now + PEER_EXPIRY), Ok(()));
+        assert_eq!(fsm.state, State::Active);
+    }
+
+    #[test]
+    fn passive_refuses_clients_while_peer_is_alive() {
+        // Split brain: clients reach us but the peer is still heartbeating.
+        let (mut fsm, now) = fsm(State::Passive);
+        assert_eq!(fsm.peer_state(State::Active, now), Ok(()));
+        let soon = now + PEER_EXPIRY / 2;
+        assert_eq!(
+            fsm.handle(Event::ClientRequest, soon),
+            Err(Refusal::NotActive)
+        );
+        assert_eq!(fsm.state, State::Passive);
+    }
+
+    #[test]
+    fn heartbeats_postpone_failover() {
+        let (mut fsm, now) = fsm(State::Passive);
+        let later = now + PEER_EXPIRY;
+        assert_eq!(fsm.peer_state(State::Active, later), Ok(()));
+        assert_eq!(
+            fsm.handle(Event::ClientRequest, later),
+            Err(Refusal::NotActive)
+        );
+    }
+
+    #[test]
+    fn every_transition_while_peer_is_alive() {
+        use Event::*;
+        use State::*;
+        let fatal = |reason| Err(Refusal::Fatal(reason));
+        #[rustfmt::skip]
+        let table = [
+            (Primary, PeerPrimary, Ok(()), Primary),
+            (Primary, PeerBackup, Ok(()), Active),
+            (Primary, PeerActive, Ok(()), Passive),
+            (Primary, PeerPassive, Ok(()), Primary),
+            (Primary, ClientRequest, Ok(()), Active),
+            (Backup, PeerPrimary, Ok(()), Backup),
+            (Backup, PeerBackup, Ok(()), Backup),
+            (Backup, PeerActive, Ok(()), Passive),
+            (Backup, PeerPassive, Ok(()), Backup),
+            (Backup, ClientRequest, Err(Refusal::NotActive), Backup),
+            (Active, PeerPrimary, Ok(()), Active),
+            (Active, PeerBackup, Ok(()), Active),
+            (Active, PeerActive, fatal("dual active brokers"), Active),
+            (Active, PeerPassive, Ok(()), Active),
+            (Active, ClientRequest, Ok(()), Active),
+            (Passive, PeerPrimary, Ok(()), Active),
+            (Passive, PeerBackup, Ok(()), Active),
+            (Passive, PeerActive, Ok(()), Passive),
+            (Passive, PeerPassive, fatal("dual passive brokers"), Passive),
+            (Passive, ClientRequest, Err(Refusal::NotActive), Passive),
+        ];
+        for (state, event, result, next) in table {
+            let (mut fsm, now) = fsm(state);
+            assert_eq!(fsm.handle(event, now), result, "{state:?} on {event:?}");
+            assert_eq!(fsm.state, next, "{state:?} on {event:?}");
+        }
+    }
+
+    #[test]
+    fn wire_format_round_trips() {
+        for state in [State::Primary, State::Backup, State::Active, State::Passive] {
+            assert_eq!(State::from_wire(state.to_wire()), Some(state));
+        }
+        assert_eq!(State::from_wire("BOGUS"), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Broker federation for the `c01_queue` example (the zguide "peering"
+//! pattern).
+//!
+//! Every broker of a federation has a name and a list of peers. Besides its
+//! local frontend (clients) and backend (workers), it binds:
+//! - a cloud ROUTER on `--cloud <addr>`, where peers send it requests;
+//! - a state PUB on the port right after the cloud one, where it announces
+//!   how many of its workers are idle.
+//!
+//! When a request arrives and no local worker is idle, it is routed to a
+//! peer that announced spare capacity, or waits for a local worker or for a
+//! peer to announce some. The reply goes back the same way.
+//! Local workers must run with `--ready`, so the broker knows which of them
+//! are idle (load-balancing pattern).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::time::{interval, timeout};
+use zeromq::{RouterSocket, SocketOptions, ZmqMessage, prelude::*};
+
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::Direction,
+};
+
+/// Time between two capacity announcements.
+const STATE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the broker waits on each attempt to reach a peer.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A peer broker, given on the command line as `NAME=addr` (the address of
+/// its cloud ROUTER).
+#[derive(Debug, Clone)]
+pub struct Peer {
+    name: String,
+    cloud: SocketAddr,
+}
+
+impl Peer {
+    /// Address of the peer's state PUB socket.
+    fn state(&self) -> SocketAddr {
+        state_addr(self.cloud)
+    }
+}
+
+impl FromStr for Peer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, addr)) = s.split_once('=') else {
+            return Err(anyhow!("Expected NAME=addr, got '{s}'"));
+        };
+        if name.is_empty() {
+            return Err(anyhow!("Empty peer name in '{s}'"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            cloud: addr.parse()?,
+        })
+    }
+}
+
+/// The state PUB socket of a broker lives on the port after its cloud one.
+fn state_addr(cloud: SocketAddr) -> SocketAddr {
+    SocketAddr::new(cloud.ip(), cloud.port() + 1)
+}
+
+fn frames(frames: Vec<Bytes>) -> ZmqMessage {
+    ZmqMessage::try_from(frames).expect("messages always have at least one frame")
+}
+
+/// Broker code for the federated mode.
+pub async fn broker_handler(
+    client_addr: SocketAddr,
+    worker_addr: SocketAddr,
+    name: String,
+    cloud_addr: SocketAddr,
+    peers: Vec<Peer>,
+    admin_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut monitors = Monitors::new(admin_addr);
+    let mut localfe = zeromq::RouterSocket::new();
+    monitors.watch("client", &mut localfe);
+    localfe
+        .bind(format!("tcp://{client_addr}").as_str())
+        .await?;
+    let mut localbe = zeromq::RouterSocket::new();
+    monitors.watch("worker", &mut localbe);
+    localbe
+        .bind(format!("tcp://{worker_addr}").as_str())
+        .await?;
+
+    // Both cloud sockets carry our name, so peers can route to us by name.
+    let mut options = SocketOptions::default();
+    options.peer_identity(name.parse()?);
+    let mut cloudfe = RouterSocket::with_options(options);
+    monitors.watch("peer", &mut cloudfe);
+    cloudfe.bind(format!("tcp://{cloud_addr}").as_str()).await?;
+    let mut options = SocketOptions::default();
+    options.peer_identity(name.parse()?);
+    let mut cloudbe = RouterSocket::with_options(options);
+
+    let mut statebe = zeromq::PubSocket::new();
+    statebe
+        .bind(format!("tcp://{}", state_addr(cloud_addr)).as_str())
+        .await?;
+    let mut statefe = zeromq::SubSocket::new();
+    statefe.subscribe("").await?;
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+
+    println!(
+        "Broker {name} ready, peers: {:?}",
+        peers.iter().map(|p| &p.name).collect::<Vec<_>>()
+    );
+    let mut unreached_cloud: Vec<Peer> = peers.clone();
+    let mut unreached_state: Vec<Peer> = peers.clone();
+    let peer_names: Vec<Bytes> = peers.iter().map(|p| Bytes::from(p.name.clone())).collect();
+
+    let mut idle_workers: VecDeque<Bytes> = VecDeque::new();
+    let mut capacity: HashMap<Bytes, usize> = HashMap::new();
+    // Requests (as [return route..., "", payload]) waiting for a worker, and
+    // whether they came from a local client.
+    let mut pending: VecDeque<(bool, Vec<Bytes>)> = VecDeque::new();
+    let mut tick = interval(STATE_INTERVAL);
+    let mut announced = None;
+
+    loop {
+        tokio::select! {
+            msg = localbe.recv() => {
+                // [worker, "", READY] or [worker, "", route..., "", reply]
+                let msg = msg?;
+                stats.record(Direction::BackendToFrontend, &msg);
+                let mut msg = msg.into_vec();
+                if msg.len() < 2 {
+                    continue;
+                }
+                let worker = msg.remove(0);
+                msg.remove(0);
+                idle_workers.push_back(worker);
+                if msg.len() > 1 {
+                    route_reply(&mut localfe, &mut cloudfe, &peer_names, msg).await?;
+                }
+            }
+            msg = cloudbe.recv() => {
+                // [peer, client, "", reply]: a reply to a request we lent out.
+                let msg = msg?;
+                stats.record(Direction::BackendToFrontend, &msg);
+                let mut msg = msg.into_vec();
+                let peer = msg.remove(0);
+                println!("Reply from {}", String::from_utf8_lossy(&peer));
+                localfe.send(frames(msg)).await?;
+            }
+            msg = statefe.recv() => {
+                // [peer, idle workers]
+                let msg = msg?.into_vec();
+                let (Some(peer), Some(idle)) = (msg.first(), msg.get(1)) else {
+                    continue;
+                };
+                let idle = String::from_utf8_lossy(idle).parse().unwrap_or(0);
+                capacity.insert(peer.clone(), idle);
+            }
+            msg = localfe.recv() => {
+                let msg = msg?;
+                stats.record(Direction::FrontendToBackend, &msg);
+                pending.push_back((true, msg.into_vec()));
+            }
+            msg = cloudfe.recv() => {
+                let msg = msg?;
+                stats.record(Direction::FrontendToBackend, &msg);
+                pending.push_back((false, msg.into_vec()));
+            }
+            _ = tick.tick() => {
+                // Connecting blocks until the peer is up, so only try briefly.
+                for peer in std::mem::take(&mut unreached_cloud) {
+                    let addr = format!("tcp://{}", peer.cloud);
+                    match timeout(PEER_CONNECT_TIMEOUT, cloudbe.connect(addr.as_str())).await {
+                        Ok(Ok(())) => println!("Connected to peer {}", peer.name),
+                        _ => unreached_cloud.push(peer),
+                    }
+                }
+                for peer in std::mem::take(&mut unreached_state) {
+                    let addr = format!("tcp://{}", peer.state());
+                    if !matches!(timeout(PEER_CONNECT_TIMEOUT, statefe.connect(addr.as_str())).await, Ok(Ok(()))) {
+                        unreached_state.push(peer);
+                    }
+                }
+                announced = None; // announce again even if nothing changed
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                stats.queue("pending", pending.len());
+                stats.queue("idle_workers", idle_workers.len());
+                stats.queue("peer_capacity", capacity.values().sum());
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
+
+        while !pending.is_empty() && !idle_workers.is_empty() {
+            let (_, mut msg) = pending.pop_front().expect("not empty");
+            let worker = idle_workers.pop_front().expect("not empty");
+            msg.splice(0..0, [worker, Bytes::new()]);
+            localbe.send(frames(msg)).await?;
+        }
+        // No local worker is left: lend local requests to the peers with
+        // capacity. Only local ones, so requests never bounce between brokers.
+        while let Some(i) = pending.iter().position(|(local, _)| *local) {
+            let Some((peer, idle)) = capacity.iter_mut().find(|(_, idle)| **idle > 0) else {
+                break;
+            };
+            *idle -= 1;
+            println!("Routing request to {}", String::from_utf8_lossy(peer));
+            let (_, mut msg) = pending.remove(i).expect("found above");
+            msg.insert(0, peer.clone());
+            cloudbe.send(frames(msg)).await?;
+        }
+        if announced != Some(idle_workers.len()) {
+            let state = vec![
+                Bytes::from(name.clone()),
+                Bytes::from(idle_workers.len().to_string()),
+            ];
+            statebe.send(frames(state)).await?;
+            announced = Some(idle_workers.len());
+        }
+    }
+}
+
+/// Sends a worker reply back where the request came from: to a peer if the
+/// return route starts with a peer name, to a local client otherwise.
+async fn route_reply(
+    localfe: &mut RouterSocket,
+    cloudfe: &mut RouterSocket,
+    peer_names: &[Bytes],
+    msg: Vec<Bytes>,
+) -> anyhow::Result<()> {
+    let Some(first) = msg.first() else {
+        return Ok(());
+    };
+    match peer_names.contains(first) {
+        true => cloudfe.send(frames(msg)).await?,
+        false => localfe.send(frames(msg)).await?,
+    }
+    Ok(())
+}
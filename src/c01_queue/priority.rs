@@ -0,0 +1,232 @@
+//! Request priorities and deadlines for the `c01_queue` broker.
+//!
+//! Clients send `[priority, deadline, payload]`, where the deadline is an
+//! absolute time in milliseconds since the Unix epoch, or empty for none.
+//! The broker keeps one queue per priority class and always serves the
+//! highest class first; requests whose deadline passed while queued are
+//! answered with `EXPIRED` instead of reaching a worker. Workers (`--ready`)
+//! get the milliseconds left before the deadline along with the payload, so
+//! they know their remaining budget.
+
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::time::{Instant, interval};
+use zeromq::{RouterSocket, ZmqMessage, prelude::*};
+
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::Direction,
+    clock::now_ms,
+};
+
+/// Reply sent instead of the worker's when a request misses its deadline.
+pub const REPLY_EXPIRED: &str = "EXPIRED";
+/// Reply sent to a request without a valid priority and deadline.
+pub const REPLY_MALFORMED: &str = "MALFORMED";
+/// How often queued requests are checked for expired deadlines.
+const EXPIRY_CHECK: Duration = Duration::from_millis(100);
+
+/// Priority class of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn to_wire(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn from_wire(s: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.to_wire().as_bytes() == s)
+    }
+}
+
+/// Parses a deadline frame: empty for none, else milliseconds since the
+/// Unix epoch.
+fn parse_deadline(frame: &[u8]) -> Option<Option<Instant>> {
+    if frame.is_empty() {
+        return Some(None);
+    }
+    let deadline = String::from_utf8_lossy(frame).parse::<u64>().ok()?;
+    let left = Duration::from_millis(deadline.saturating_sub(now_ms()));
+    Some(Some(Instant::now() + left))
+}
+
+fn expired(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.is_some_and(|deadline| deadline <= now)
+}
+
+/// A request waiting in the broker.
+struct Request {
+    client: Bytes,
+    deadline: Option<Instant>,
+    payload: Bytes,
+}
+
+fn frames(frames: Vec<Bytes>) -> ZmqMessage {
+    ZmqMessage::try_from(frames).expect("messages always have at least one frame")
+}
+
+/// Answers `client` with `reply` instead of a worker.
+async fn answer(
+    frontend: &mut RouterSocket,
+    client: Bytes,
+    reply: &'static str,
+) -> anyhow::Result<()> {
+    frontend
+        .send(frames(vec![client, Bytes::new(), reply.into()]))
+        .await?;
+    Ok(())
+}
+
+/// Broker code for the prioritized mode.
+/// Clients connect to a ROUTER socket and `--ready` workers to another one,
+/// so the broker knows which workers are idle and picks the request to send.
+pub async fn broker_handler(
+    client_addr: SocketAddr,
+    worker_addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut monitors = Monitors::new(admin_addr);
+    let mut frontend = zeromq::RouterSocket::new();
+    monitors.watch("client", &mut frontend);
+    frontend
+        .bind(format!("tcp://{client_addr}").as_str())
+        .await?;
+    let mut backend = zeromq::RouterSocket::new();
+    monitors.watch("worker", &mut backend);
+    backend
+        .bind(format!("tcp://{worker_addr}").as_str())
+        .await?;
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+
+    let mut queues: [VecDeque<Request>; 3] = Default::default();
+    let mut idle_workers: VecDeque<Bytes> = VecDeque::new();
+    let mut expiry_check = interval(EXPIRY_CHECK);
+    loop {
+        tokio::select! {
+            msg = frontend.recv() => {
+                // [client, "", priority, deadline, payload]
+                let msg = msg?;
+                stats.record(Direction::FrontendToBackend, &msg);
+                let msg = msg.into_vec();
+                let Some(client) = msg.first().cloned() else {
+                    continue;
+                };
+                let [_, _, priority, deadline, payload] = msg.as_slice() else {
+                    eprintln!("ERROR: Malformed request");
+                    answer(&mut frontend, client, REPLY_MALFORMED).await?;
+                    continue;
+                };
+                let priority = Priority::from_wire(priority);
+                let deadline = parse_deadline(deadline);
+                let (Some(priority), Some(deadline)) = (priority, deadline) else {
+                    eprintln!("ERROR: Bad priority or deadline");
+                    answer(&mut frontend, client, REPLY_MALFORMED).await?;
+                    continue;
+                };
+                queues[priority as usize].push_back(Request {
+                    client,
+                    deadline,
+                    payload: payload.clone(),
+                });
+            }
+            msg = backend.recv() => {
+                // [worker, "", READY] or [worker, "", client, "", reply]
+                let msg = msg?;
+                stats.record(Direction::BackendToFrontend, &msg);
+                let mut msg = msg.into_vec();
+                idle_workers.push_back(msg.remove(0));
+                if msg.len() > 2 {
+                    frontend.send(frames(msg.split_off(1))).await?;
+                }
+            }
+            _ = expiry_check.tick() => {
+                let now = Instant::now();
+                for queue in queues.iter_mut() {
+                    let (gone, alive) = std::mem::take(queue)
+                        .into_iter()
+                        .partition::<VecDeque<_>, _>(|r| expired(r.deadline, now));
+                    *queue = alive;
+                    for request in gone {
+                        println!("Request from {:?} expired", request.client);
+                        answer(&mut frontend, request.client, REPLY_EXPIRED).await?;
+                    }
+                }
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                for priority in Priority::ALL {
+                    stats.queue(priority.to_wire(), queues[priority as usize].len());
+                }
+                stats.queue("idle_workers", idle_workers.len());
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
+
+        let now = Instant::now();
+        while !idle_workers.is_empty() {
+            let Some(request) = queues.iter_mut().find_map(|q| q.pop_front()) else {
+                break;
+            };
+            // It may have expired since the last check
+            if expired(request.deadline, now) {
+                println!("Request from {:?} expired", request.client);
+                answer(&mut frontend, request.client, REPLY_EXPIRED).await?;
+                continue;
+            }
+            let worker = idle_workers.pop_front().expect("not empty");
+            let mut msg = vec![worker, Bytes::new(), request.client, Bytes::new()];
+            if let Some(deadline) = request.deadline {
+                let left = deadline.saturating_duration_since(now).as_millis();
+                msg.push(Bytes::from(left.to_string()));
+            }
+            msg.push(request.payload);
+            backend.send(frames(msg)).await?;
+        }
+    }
+}
+
+/// Client code for the prioritized mode.
+/// Sends "Hello" 10 times with the given priority and, with a `budget`, a
+/// deadline that long after each send.
+pub async fn client_handler(
+    addr: SocketAddr,
+    priority: Priority,
+    budget: Option<Duration>,
+) -> anyhow::Result<()> {
+    println!("Connecting to hello world server ({priority:?}, {budget:?} budget)...");
+    let mut sock = zeromq::ReqSocket::new();
+    sock.connect(format!("tcp://{addr}").as_str()).await?;
+
+    for i in 0..10 {
+        println!("Sending Hello {i}...");
+        let deadline = budget.map(|budget| now_ms() + budget.as_millis() as u64);
+        let msg = vec![
+            Bytes::from_static(priority.to_wire().as_bytes()),
+            deadline.map_or_else(Bytes::new, |deadline| deadline.to_string().into()),
+            Bytes::from_static(b"Hello"),
+        ];
+        sock.send(frames(msg)).await?;
+        let reply = String::try_from(sock.recv().await?).map_err(|e| anyhow!(e))?;
+        match reply.as_str() {
+            REPLY_EXPIRED => println!("Hello {i} expired"),
+            REPLY_MALFORMED => println!("Hello {i} was rejected as malformed"),
+            _ => println!("Received {reply} {i}..."),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! Disk-backed broker for the `c01_queue` example (the zguide "Titanic" pattern).
+//!
+//! Every request accepted from a client is written to the store before the
+//! client gets its request ID back, so a broker restart does not lose it.
+//! Requests are handed to the `--ready` workers as soon as one is idle and
+//! the replies are stored until the client fetches them (`poll`) and
+//! discards them (`close`).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use rand::Rng;
+use tokio::time::{Instant, interval};
+use zeromq::{ZmqMessage, prelude::*};
+
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::{self, Direction, Tap},
+};
+
+/// Status code sent back to the client when the command succeeded.
+pub const STATUS_OK: &str = "200";
+/// Status code sent back to the client when the reply is not ready yet.
+pub const STATUS_PENDING: &str = "300";
+/// Status code sent back to the client when the request ID is unknown.
+pub const STATUS_UNKNOWN: &str = "400";
+/// Status code sent back to the client when the command is malformed.
+pub const STATUS_INVALID: &str = "500";
+
+/// How long a request may go unanswered by the idle worker it was sent to
+/// before it is sent again.
+const REDISPATCH_AFTER: Duration = Duration::from_secs(10);
+
+/// Request store kept inside a directory:
+/// - `requests/<id>`: the payload of every request not yet closed;
+/// - `replies/<id>`: the reply of every request already answered by a worker.
+///
+/// Writes go to a temporary file that is synced and then renamed, so a crash
+/// never leaves a half-written request or reply behind.
+pub struct Store {
+    requests: PathBuf,
+    replies: PathBuf,
+}
+
+impl Store {
+    /// Opens (creating if needed) the store at `dir`.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let requests = dir.join("requests");
+        let replies = dir.join("replies");
+        std::fs::create_dir_all(&requests)?;
+        std::fs::create_dir_all(&replies)?;
+        Ok(Self { requests, replies })
+    }
+
+    /// Saves a new request and returns its ID.
+    pub fn submit(&self, payload: &[u8]) -> anyhow::Result<String> {
+        let id = format!("{:032x}", rand::rng().random::<u128>());
+        write_atomic(&self.requests.join(&id), payload)?;
+        Ok(id)
+    }
+
+    /// Reads the payload of a request.
+    pub fn request(&self, id: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(std::fs::read(self.requests.join(valid_id(id)?))?)
+    }
+
+    /// Saves the reply of a request, unless it was closed in the meantime.
+    pub fn store_reply(&self, id: &str, reply: &[u8]) -> anyhow::Result<()> {
+        let id = valid_id(id)?;
+        if !self.requests.join(id).exists() {
+            return Ok(());
+        }
+        write_atomic(&self.replies.join(id), reply)
+    }
+
+    /// Reads the reply of a request, `None` if it is still pending.
+    pub fn reply(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let id = valid_id(id)?;
+        if !self.requests.join(id).exists() {
+            return Err(anyhow!("Unknown request '{id}'"));
+        }
+        match std::fs::read(self.replies.join(id)) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Forgets a request and its reply.
+    pub fn close(&self, id: &str) -> anyhow::Result<()> {
+        let id = valid_id(id)?;
+        for path in [self.replies.join(id), self.requests.join(id)] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// IDs of the requests that still have no reply, oldest first.
+    pub fn pending(&self) -> anyhow::Result<Vec<String>> {
+        let mut pending = Vec::new();
+        for entry in std::fs::read_dir(&self.requests)? {
+            let entry = entry?;
+            let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if valid_id(&id).is_err() || self.replies.join(&id).exists() {
+                continue;
+            }
+            pending.push((entry.metadata()?.modified()?, id));
+        }
+        pending.sort();
+        Ok(pending.into_iter().map(|(_, id)| id).collect())
+    }
+}
+
+/// Rejects IDs that could escape the store directory.
+fn valid_id(id: &str) -> anyhow::Result<&str> {
+    match !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Ok(id),
+        false => Err(anyhow!("Invalid request id '{id}'")),
+    }
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = PathBuf::from(path);
+    tmp.as_mut_os_string().push(".tmp");
+    let mut file = File::create(&tmp)?;
+    std::io::Write::write_all(&mut file, contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // Make the rename itself durable
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Builds a multipart message out of its frames.
+fn multipart<I: IntoIterator<Item = Bytes>>(frames: I) -> ZmqMessage {
+    ZmqMessage::try_from(frames.into_iter().collect::<Vec<_>>())
+        .expect("multipart messages always have at least one frame")
+}
+
+/// Broker code for the persistent mode.
+/// Clients talk to a ROUTER socket with `submit`, `poll` and `close`
+/// commands; requests are forwarded to idle `--ready` workers through
+/// another ROUTER socket, tagged with their ID so the replies can be matched.
+/// A worker gets one request at a time, so a request is only sent again if
+/// the worker that has it does not answer in time.
+pub async fn broker_handler(
+    client_addr: SocketAddr,
+    worker_addr: SocketAddr,
+    dir: PathBuf,
+    capture_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let store = Store::open(&dir)?;
+    let mut queue: VecDeque<String> = store.pending()?.into();
+    let mut in_flight: HashMap<String, Instant> = HashMap::new(); // Sent to a worker at
+    let mut idle_workers: VecDeque<Bytes> = VecDeque::new();
+    println!(
+        "Recovered {} pending requests from {}",
+        queue.len(),
+        dir.display()
+    );
+
+    let mut monitors = Monitors::new(admin_addr);
+    let mut frontend = zeromq::RouterSocket::new();
+    monitors.watch("client", &mut frontend);
+    frontend
+        .bind(format!("tcp://{client_addr}").as_str())
+        .await?;
+    let mut backend = zeromq::RouterSocket::new();
+    monitors.watch("worker", &mut backend);
+    backend
+        .bind(format!("tcp://{worker_addr}").as_str())
+        .await?;
+    let mut tap = Tap::bind(capture_addr).await?;
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+
+    let mut tick = interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            msg = frontend.recv() => {
+                let msg = msg?;
+                capture::capture(&mut tap, Direction::FrontendToBackend, &msg).await;
+                stats.record(Direction::FrontendToBackend, &msg);
+                let reply = handle_command(&store, &mut queue, msg.into_vec());
+                capture::capture(&mut tap, Direction::BackendToFrontend, &reply).await;
+                stats.record(Direction::BackendToFrontend, &reply);
+                frontend.send(reply).await?;
+            }
+            msg = backend.recv() => {
+                // Workers send [worker, "", READY], then reply with [worker, "", id, "", reply].
+                let msg = msg?;
+                capture::capture(&mut tap, Direction::BackendToFrontend, &msg).await;
+                stats.record(Direction::BackendToFrontend, &msg);
+                let frames = msg.into_vec();
+                match frames.as_slice() {
+                    [worker, _, _] => idle_workers.push_back(worker.clone()),
+                    [worker, _, id, _, reply] => {
+                        idle_workers.push_back(worker.clone());
+                        let id = String::from_utf8_lossy(id).to_string();
+                        if in_flight.remove(&id).is_some() {
+                            store.store_reply(&id, reply)?;
+                            println!("Stored reply for {id}");
+                        }
+                    }
+                    _ => eprintln!("ERROR: Malformed worker reply"),
+                }
+            }
+            _ = tick.tick() => {
+                let now = Instant::now();
+                in_flight.retain(|id, sent| match now - *sent < REDISPATCH_AFTER {
+                    true => true,
+                    false => {
+                        // The worker is gone or stuck: it is not idle until it answers
+                        println!("No answer for {id}, sending it again");
+                        queue.push_back(id.clone());
+                        false
+                    }
+                });
+                while !queue.is_empty() && !idle_workers.is_empty() {
+                    let id = queue.pop_front().expect("not empty");
+                    let payload = match store.request(&id) {
+                        Ok(payload) => payload,
+                        Err(_) => continue, // closed before being dispatched
+                    };
+                    let worker = idle_workers.pop_front().expect("not empty");
+                    let msg = multipart([worker, Bytes::new(), Bytes::from(id.clone()), Bytes::new(), payload.into()]);
+                    capture::capture(&mut tap, Direction::FrontendToBackend, &msg).await;
+                    stats.record(Direction::FrontendToBackend, &msg);
+                    if backend.send(msg).await.is_err() {
+                        // The worker left while idle: try the next one
+                        queue.push_front(id);
+                        continue;
+                    }
+                    in_flight.insert(id, now);
+                }
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                stats.queue("pending", queue.len());
+                stats.queue("in_flight", in_flight.len());
+                stats.queue("idle_workers", idle_workers.len());
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
+    }
+}
+
+/// Handles a client command, received as [identity, "", command, arg],
+/// and builds the reply to send back through the ROUTER socket.
+fn handle_command(store: &Store, queue: &mut VecDeque<String>, frames: Vec<Bytes>) -> ZmqMessage {
+    let identity = frames[0].clone();
+    let status = |frames: &[&str]| {
+        multipart(
+            [identity.clone(), Bytes::new()]
+                .into_iter()
+                .chain(frames.iter().map(|f| Bytes::from(f.to_string()))),
+        )
+    };
+
+    let (cmd, arg) = match frames.as_slice() {
+        [_, _, cmd, arg] => (cmd, arg),
+        _ => return status(&[STATUS_INVALID]),
+    };
+    let id = String::from_utf8_lossy(arg);
+    match cmd.as_ref() {
+        // Workers find the reply after the last empty frame, so the payload
+        // must not be one
+        b"submit" if arg.is_empty() => status(&[STATUS_INVALID]),
+        b"submit" => match store.submit(arg) {
+            Ok(id) => {
+                println!("Accepted request {id}");
+                queue.push_back(id.clone());
+                status(&[STATUS_OK, &id])
+            }
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                status(&[STATUS_INVALID])
+            }
+        },
+        b"poll" => match store.reply(&id) {
+            Ok(Some(reply)) => multipart([identity, Bytes::new(), STATUS_OK.into(), reply.into()]),
+            Ok(None) => status(&[STATUS_PENDING]),
+            Err(_) => status(&[STATUS_UNKNOWN]),
+        },
+        b"close" => match store.close(&id) {
+            Ok(()) => status(&[STATUS_OK]),
+            Err(_) => status(&[STATUS_UNKNOWN]),
+        },
+        _ => status(&[STATUS_INVALID]),
+    }
+}
+
+/// Client code for the persistent mode.
+/// Sends a single command to the broker and prints the answer.
+pub async fn command_handler(addr: SocketAddr, cmd: &str, arg: String) -> anyhow::Result<()> {
+    let mut sock = zeromq::ReqSocket::new();
+    sock.connect(format!("tcp://{addr}").as_str()).await?;
+    sock.send(multipart([
+        Bytes::from(cmd.to_string()),
+        Bytes::from(arg.clone()),
+    ]))
+    .await?;
+
+    let frames = sock.recv().await?.into_vec();
+    let status = frames
+        .first()
+        .map(|s| String::from_utf8_lossy(s).to_string());
+    match (cmd, status.as_deref(), frames.get(1)) {
+        ("submit", Some(STATUS_OK), Some(id)) => println!("{}", String::from_utf8_lossy(id)),
+        ("poll", Some(STATUS_OK), Some(reply)) => println!("{}", String::from_utf8_lossy(reply)),
+        ("poll", Some(STATUS_PENDING), _) => println!("Request {arg} is still pending"),
+        ("close", Some(STATUS_OK), _) => println!("Closed request {arg}"),
+        (_, Some(STATUS_UNKNOWN), _) => return Err(anyhow!("Unknown request '{arg}'")),
+        (_, status, _) => return Err(anyhow!("Broker refused '{cmd}': {status:?}")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(store: &Store, queue: &mut VecDeque<String>, cmd: &str, arg: &str) -> Vec<Bytes> {
+        let frames = vec![
+            Bytes::from("client"),
+            Bytes::new(),
+            Bytes::from(cmd.to_string()),
+            Bytes::from(arg.to_string()),
+        ];
+        handle_command(store, queue, frames).into_vec()
+    }
+
+    #[test]
+    fn empty_payloads_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("titanic-{}", std::process::id()));
+        let store = Store::open(&dir).expect("store opens");
+        let mut queue = VecDeque::new();
+
+        let reply = command(&store, &mut queue, "submit", "");
+        assert_eq!(reply[2], STATUS_INVALID);
+        assert!(queue.is_empty());
+        assert!(store.pending().expect("store lists").is_empty());
+
+        let reply = command(&store, &mut queue, "submit", "Hello");
+        assert_eq!(reply[2], STATUS_OK);
+        assert_eq!(queue, [String::from_utf8_lossy(&reply[3])]);
+        std::fs::remove_dir_all(&dir).expect("store removed");
+    }
+}
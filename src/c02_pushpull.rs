@@ -1,23 +1,126 @@
-use std::{io::Write, net::SocketAddr, time::Duration};
-use clap::Parser;
-use rand::Rng;
-use tokio::time::{sleep, Instant};
+use std::{collections::VecDeque, future::pending, io::Write, net::SocketAddr, path::PathBuf, time::Duration};
+use bytes::Bytes;
+use clap::{CommandFactory, Parser};
+use tokio::time::{interval, sleep, Instant};
 use zeromq::prelude::*;
 
+mod batches;
+mod checkpoint;
+mod credit;
+mod jobs;
+mod mapreduce;
+mod registry;
+mod stats;
+mod tracker;
+
+use batches::{Added, Batches, Start, START};
+use checkpoint::Checkpoint;
+use credit::{TaskReceiver, TaskSender};
+use jobs::Kind;
+use registry::{worker_id, Registration, Registry};
+use stats::Stats;
+use tracker::{Task, TaskResult, Tracker, DONE};
+
+/// Published by the sink on the control socket once the last batch is done.
+const KILL: &str = "KILL";
+/// How often the ventilator looks for tasks that timed out.
+const TIMEOUT_CHECK: Duration = Duration::from_millis(100);
+/// How often to poll a PULL socket again while waiting.
+const REPOLL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, clap::Subcommand)]
 enum Mode {
     /// Run the Ventilator, specifying the sink addr and bind addr for workers.
+    /// Workers register on the register addr; the ventilator starts once
+    /// `--min-workers` did, or after `--wait` seconds.
     Ventilator {
         sender: SocketAddr,
         sink: SocketAddr,
+        register: SocketAddr,
+        /// Number of workers to wait for before sending the tasks.
+        #[arg(long, default_value_t = 1)]
+        min_workers: usize,
+        /// Start anyway after this many seconds, with the workers registered by then.
+        #[arg(long)]
+        wait: Option<u64>,
+        /// Wait for the KILL message of the sink on this addr before exiting,
+        /// and send again the tasks the sink did not report as done in time.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+        /// Milliseconds to wait for the result of a task before sending it again.
+        #[arg(long, default_value_t = 2000)]
+        task_timeout: u64,
+        /// Kind of the tasks to send.
+        #[arg(long, value_enum, default_value_t = Kind::Sleep)]
+        job: Kind,
+        /// Only send tasks to the workers with credit; the workers must be
+        /// started with `--credit` too.
+        #[arg(long)]
+        credit: bool,
+        /// Number of tasks in each batch.
+        #[arg(long, default_value_t = 100)]
+        batch_size: u64,
+        /// Number of batches to send.
+        #[arg(long, default_value_t = 1, conflicts_with = "continuous")]
+        batches: u64,
+        /// Send batches until interrupted.
+        #[arg(long)]
+        continuous: bool,
+        /// Milliseconds between the starts of two batches.
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Skip the tasks already done in this checkpoint of the sink, and
+        /// send the others again with the same payloads.
+        #[arg(long)]
+        resume: Option<PathBuf>,
     },
-    /// Run the Worker, specifying the sink and ventilator addresses.
+    /// Run the Worker, specifying the ventilator, sink and registration addresses.
     Worker {
         receiver: SocketAddr,
         sender: SocketAddr,
+        register: SocketAddr,
+        /// Exit when the sink publishes KILL on this addr.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+        /// Take up to this many tasks at once from a ventilator in credit mode.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        credit: Option<u32>,
     },
     /// Run the Sink, specifying the bind addr for workers.
-    Sink { receiver: SocketAddr },
+    Sink {
+        receiver: SocketAddr,
+        /// Publish KILL on a PUB socket bound here once the last batch is done.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+        /// Also write the statistics of the workers to this file as JSON.
+        #[arg(long)]
+        json: Option<PathBuf>,
+        /// Save the finished batches and the results so far to this file,
+        /// and resume from it if it exists.
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+        /// Milliseconds between two checkpoints.
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        checkpoint_interval: u64,
+    },
+    /// Run a MapReduce job on the pipeline.
+    Mapreduce {
+        #[command(subcommand)]
+        role: mapreduce::Role,
+    },
+}
+
+/// How many workers to wait for, and what to send them.
+struct Batch {
+    min_workers: usize,
+    wait: Option<Duration>,
+    kind: Kind,
+    size: u64,
+    /// Number of batches, or `None` to send them until interrupted.
+    count: Option<u64>,
+    interval: Duration,
+    /// Checkpoint of the sink to resume from.
+    resume: Option<Checkpoint>,
 }
 
 #[derive(clap::Parser)]
@@ -26,6 +129,11 @@ struct Cli {
     cmd: Mode,
 }
 
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
 pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -34,96 +142,347 @@ pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<(
 }
 
 pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
-        Mode::Ventilator { sender, sink } => ventilator_handler(sender, sink).await,
-        Mode::Worker { receiver, sender } => worker_handler(receiver, sender).await,
-        Mode::Sink { receiver } => sink_handler(receiver).await,
+        Mode::Ventilator { sender, sink, register, min_workers, wait, control, task_timeout, job, credit, batch_size, batches, continuous, interval, resume } => {
+            let sender = TaskSender::bind(sender, credit).await?;
+            let registry = Registry::bind(register, credit).await?;
+            let tracker = Tracker::new(Duration::from_millis(task_timeout));
+            let batch = Batch {
+                min_workers,
+                wait: wait.map(Duration::from_secs),
+                kind: job,
+                size: batch_size,
+                count: (!continuous).then_some(batches),
+                interval: Duration::from_millis(interval),
+                resume: resume.as_deref().map(Checkpoint::load).transpose()?,
+            };
+            ventilator_handler(sender, sink, registry, batch, control, tracker).await
+        }
+        Mode::Worker { receiver, sender, register, control, credit } => worker_handler(receiver, sender, register, control, credit).await,
+        Mode::Sink { receiver, control, json, checkpoint, checkpoint_interval } => sink_handler(receiver, control, json, checkpoint, Duration::from_millis(checkpoint_interval)).await,
+        Mode::Mapreduce { role } => mapreduce::main(role).await,
     }
 }
 
-async fn ventilator_handler(sender_addr: SocketAddr, sink_addr: SocketAddr) -> anyhow::Result<()> {
-    let mut sender = zeromq::PushSocket::new();
-    sender.bind(format!("tcp://{sender_addr}").as_str()).await?;
+/// Subscribes to the `topics` of the control socket of the sink, if a control addr was given.
+async fn control_socket(control_addr: Option<SocketAddr>, topics: &[&str]) -> anyhow::Result<Option<zeromq::SubSocket>> {
+    let Some(control_addr) = control_addr else {
+        return Ok(None);
+    };
+    let mut control = zeromq::SubSocket::new();
+    for topic in topics {
+        control.subscribe(topic).await?;
+    }
+    control.connect(format!("tcp://{control_addr}").as_str()).await?;
+    Ok(Some(control))
+}
+
+/// Waits for the next control message; never returns if there is no control socket.
+async fn control_recv(control: &mut Option<zeromq::SubSocket>) -> anyhow::Result<String> {
+    match control {
+        Some(control) => String::try_from(control.recv().await?).map_err(|e| anyhow::anyhow!(e)),
+        None => pending().await,
+    }
+}
+
+/// Receives from a PULL socket with several peers. The `zeromq` crate can
+/// leave the message of a peer waiting until the socket is polled again, so
+/// the last results would never arrive without this.
+async fn pull_recv(sock: &mut zeromq::PullSocket) -> zeromq::ZmqResult<zeromq::ZmqMessage> {
+    loop {
+        if let Ok(msg) = tokio::time::timeout(REPOLL, sock.recv()).await {
+            return msg;
+        }
+    }
+}
+
+async fn ventilator_handler(
+    mut sender: TaskSender,
+    sink_addr: SocketAddr,
+    mut registry: Registry,
+    batch: Batch,
+    control_addr: Option<SocketAddr>,
+    mut tracker: Tracker,
+) -> anyhow::Result<()> {
     let mut sink = zeromq::PushSocket::new();
     sink.connect(format!("tcp://{sink_addr}").as_str()).await?;
+    let mut control = control_socket(control_addr, &[KILL, DONE]).await?;
 
-    println!("Press Enter when the workers are ready: ");
-    std::io::stdin().read_line(&mut String::new())?;
-    println!("Sending tasks to workers...");
+    println!("Waiting for {} workers to register...", batch.min_workers);
+    let workers = registry.wait_for(batch.min_workers, batch.wait).await?;
+    println!("Sending {} tasks to {workers} workers...", batch.kind);
 
-    sink.send("0".into()).await?; // Signal the start of a batch
-
-    let mut rng = rand::rng();
-    let mut total_msec = 0;
-    for _ in 0..100 {
-        let workload = rng.random_range(1..100u64);
-        total_msec += workload;
-        sender.send(format!("{workload}").into()).await?
+    let mut queue: VecDeque<Task> = VecDeque::new();
+    let mut next_batch = 0;
+    let mut count = batch.count;
+    if let Some(checkpoint) = &batch.resume {
+        queue.extend(checkpoint.remaining());
+        next_batch = checkpoint.next_batch();
+        count = checkpoint.last.map(|last| last + 1).or(count);
+        println!("Resuming: {} batches done, {} tasks left in {} batches", checkpoint.finished.len(), queue.len(), checkpoint.running.len());
+    }
+    let mut batch_tick = interval(batch.interval);
+    let mut timeout_check = interval(TIMEOUT_CHECK);
+    loop {
+        // Only send tasks that some worker can take now
+        while !queue.is_empty() {
+            let worker = match sender.is_credit() {
+                true => match registry.take_credit() {
+                    Some(worker) => Some(worker),
+                    None => break,
+                },
+                false => match registry.take_ready() {
+                    true => None,
+                    false => break,
+                },
+            };
+            let task = queue.pop_front().expect("not empty");
+            if let Err(e) = sender.send(worker.as_deref(), task.encode()).await {
+                // The worker it went to is gone: try again on the next event
+                eprintln!("Error sending task {}/{}: {e}", task.batch, task.id);
+                queue.push_front(task);
+                match worker {
+                    Some(worker) => registry.forget(&worker),
+                    None => registry.refund_ready(),
+                }
+                break;
+            }
+            if control.is_some() {
+                tracker.sent(task, Instant::now());
+            }
+        }
+        let all_sent = count.is_some_and(|count| next_batch >= count);
+        if control.is_none() && all_sent && queue.is_empty() {
+            break;  // Nobody tells us what is done: just send everything once
+        }
+        
+        tokio::select! {
+            _ = batch_tick.tick(), if !all_sent => {
+                // Signal the start of a batch, with what the sink needs to check the results
+                let start = Start {
+                    batch: next_batch,
+                    kind: batch.kind,
+                    seed: rand::random(),
+                    size: batch.size,
+                    last: count == Some(next_batch + 1),
+                };
+                sink.send(start.encode()).await?;
+                
+                let job = jobs::job(start.kind, start.seed, start.size);
+                queue.extend((0..start.size).map(|id| Task { batch: start.batch, id, kind: start.kind, payload: job.payload(id) }));
+                match job.expected_cost() {
+                    Some(total_msec) => println!("Batch {next_batch}: {} tasks, total expected cost: {total_msec} msec", start.size),
+                    None => println!("Batch {next_batch}: {} tasks", start.size),
+                }
+                next_batch += 1;
+            }
+            taken = registry.recv() => {
+                if let Some(taken) = taken? {
+                    tracker.taken(taken.batch, taken.task, taken.worker);
+                }
+            }
+            msg = control_recv(&mut control) => {
+                let msg = msg?;
+                let words: Vec<&str> = msg.split_whitespace().collect();
+                match words.as_slice() {
+                    [DONE, batch, id] => match (batch.parse(), id.parse()) {
+                        (Ok(batch), Ok(id)) => tracker.done(batch, id),
+                        _ => eprintln!("ERROR: Malformed control message {msg:?}"),
+                    },
+                    [KILL] => break,
+                    _ => eprintln!("ERROR: Unexpected control message {msg:?}"),
+                }
+            }
+            _ = timeout_check.tick(), if control.is_some() => {
+                queue.extend(tracker.expired(Instant::now()));
+            }
+        }
     }
     
-    println!("Total expected cost: {total_msec} msec");
+    if control.is_some() {
+        println!("All batches done, exiting");
+        tracker.report();
+    }
     
     sink.close().await;
     sender.close().await;
     Ok(())
 }
 
-async fn worker_handler(receiver_addr: SocketAddr, sender_addr: SocketAddr) -> anyhow::Result<()> {
-    let mut receiver = zeromq::PullSocket::new();
-    receiver.connect(format!("tcp://{receiver_addr}").as_str()).await?;
+async fn worker_handler(receiver_addr: SocketAddr, sender_addr: SocketAddr, register_addr: SocketAddr, control_addr: Option<SocketAddr>, credit: Option<u32>) -> anyhow::Result<()> {
+    let id = worker_id();
+    // Connected before registering, so the ventilator can reach it by its id
+    let mut receiver = TaskReceiver::connect(receiver_addr, &id, credit.is_some()).await?;
     let mut sender = zeromq::PushSocket::new();
     sender.connect(format!("tcp://{sender_addr}").as_str()).await?;
+    let mut control = control_socket(control_addr, &[KILL]).await?;
+    let mut registration = Registration::connect(register_addr, id, credit.map_or(1, |credit| credit as usize)).await?;
+    println!("Registered as worker {}", registration.id());
     
     loop {
-        let bytes = match receiver.recv().await {
-            Ok(bytes) => bytes,
-            Err(e) => {eprintln!("{e}"); continue;}
+        let bytes = tokio::select! {
+            bytes = receiver.recv() => bytes,
+            msg = control_recv(&mut control) => {
+                msg?;
+                println!("Batch done, exiting");
+                return Ok(());
+            }
         };
-        
-        let msg = match String::try_from(bytes) {
-            Ok(msg) => msg,
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
             Err(e) => {eprintln!("{e}"); continue;}
         };
         
-        let num = match msg.parse::<u64>() {
-            Ok(num) => num,
+        let task = match Task::decode(bytes) {
+            Ok(task) => task,
             Err(e) => {eprintln!("{e}"); continue;}
         };
+        registration.taken(task.batch, task.id).await?;
         
-        println!("Got task: Sleep for {num} msec");
+        println!("Got task {}/{}: {}", task.batch, task.id, task.kind);
         
-        sleep(Duration::from_millis(num)).await;  // Faking a long computation
-        sender.send("".into()).await?;    // "" is the result of the computation
+        let (batch, id, kind) = (task.batch, task.id, task.kind);
+        let started = Instant::now();
+        let (result, error) = match tokio::task::spawn_blocking(move || jobs::run(kind, &task.payload)).await? {
+            Ok(result) => (result, None),
+            Err(e) => {
+                // Tell the sink, which decides whether to try it again
+                eprintln!("ERROR: Task {batch}/{id} failed: {e:#}");
+                (Bytes::new(), Some(format!("{e:#}")))
+            }
+        };
+        let busy = (Instant::now() - started).as_millis() as u64;
+        let worker = registration.id().to_string();
+        sender.send(TaskResult { batch, id, worker, result, busy, error }.encode()).await?;
+        registration.ready().await?;
     }
 }
 
-async fn sink_handler(receiver_addr: SocketAddr) -> anyhow::Result<()> {
+async fn sink_handler(receiver_addr: SocketAddr, control_addr: Option<SocketAddr>, json: Option<PathBuf>, checkpoint: Option<PathBuf>, checkpoint_interval: Duration) -> anyhow::Result<()> {
     let mut receiver = zeromq::PullSocket::new();
     receiver.bind(format!("tcp://{receiver_addr}").as_str()).await?;
+    let mut control = zeromq::PubSocket::new();
+    if let Some(control_addr) = control_addr {
+        control.bind(format!("tcp://{control_addr}").as_str()).await?;
+    }
         
-    let start_of_batch = String::try_from(receiver.recv().await?).expect("Failed to get string from message.");
-    assert!("0".to_string() == start_of_batch);
-        
-    let start = Instant::now();
-    
-    for task_number in 0..100 {
-        let msg = String::try_from(receiver.recv().await?).expect("Failed to get string from message.");
-        assert!(msg.is_empty());
+    let mut batches = Batches::default();
+    let mut start: Option<Instant> = None;  // Of the first batch
+    if let Some(path) = checkpoint.as_ref().filter(|path| path.exists()) {
+        let saved = Checkpoint::load(path)?;
+        println!("Resuming from {}: {} batches done, {} running", path.display(), saved.finished.len(), saved.running.len());
+        let now = Instant::now();
+        start = Some(now);
+        batches = Batches::resume(saved, now);
+    }
+    let mut checkpoint_tick = interval(checkpoint_interval);
+    let mut changed = false;  // Since the last checkpoint
+    let mut results = 0;
+    let mut duplicates = 0;
+    let mut failed = 0;
+    let mut wrong = 0;
+    let mut stats = Stats::default();
+    while !batches.all_done() {
+        let msg = tokio::select! {
+            msg = pull_recv(&mut receiver) => msg?.into_vec(),
+            _ = checkpoint_tick.tick(), if checkpoint.is_some() => {
+                if let Some(path) = checkpoint.as_ref().filter(|_| changed) {
+                    batches.checkpoint().save(path)?;
+                    changed = false;
+                }
+                continue;
+            }
+        };
+        changed = true;
+        if msg.first().is_some_and(|frame| frame.as_ref() == START.as_bytes()) {
+            let now = Instant::now();
+            start.get_or_insert(now);
+            let start = Start::decode(&msg)?;
+            let batch = start.batch;
+            for id in batches.start(start, now) {
+                if control_addr.is_some() {
+                    // Results that arrived before the start, and are done now
+                    control.send(format!("{DONE} {batch} {id}").into()).await?;
+                }
+            }
+            if let Some(path) = &checkpoint {
+                // Right away, so a resumed ventilator does not start this batch again
+                batches.checkpoint().save(path)?;
+            }
+        } else {
+            let result = match TaskResult::decode(&msg) {
+                Ok(result) => result,
+                Err(e) => {eprintln!("ERROR: Malformed result: {e}"); continue;}
+            };
+            stats.record(&result);
+            let (batch, id) = (result.batch, result.id);
+            let added = batches.add(result);
+            if control_addr.is_some() && added.is_done() {
+                // Tell the ventilator not to send it again
+                control.send(format!("{DONE} {batch} {id}").into()).await?;
+            }
+            match added {
+                Added::New | Added::Early => {}
+                Added::Duplicate => {
+                    duplicates += 1;
+                    continue;
+                }
+                Added::Failed | Added::GaveUp => {
+                    failed += 1;
+                    continue;
+                }
+                Added::Rejected => continue,
+            }
+            
+            results += 1;
+            match (results - 1) % 10 == 0 {
+                true => print!(":"),
+                false => print!("."),
+            }
+            std::io::stdout().flush()?;
+        }
         
-        std::io::stdout().flush()?;
-        match task_number % 10 == 0 {
-            true => print!(":"),
-            false => print!("."),
+        for finished in batches.take_finished(Instant::now()) {
+            println!();
+            println!("Batch {} done: {} tasks in {} msec, {:.1} tasks/sec", finished.batch, finished.size, finished.elapsed.as_millis(), finished.throughput());
+            stats.finished(finished.expected_cost);
+            match &finished.verified {
+                Ok(summary) => println!("Verified {} results: {summary}", finished.kind),
+                Err(e) => {
+                    println!("Wrong {} results: {e:#}", finished.kind);
+                    wrong += 1;
+                }
+            }
         }
-        std::io::stdout().flush()?;
     }
-    println!("");
     
-    let elapsed_time = (Instant::now() - start).as_millis();
+    let elapsed_time = start.map_or(0, |start| (Instant::now() - start).as_millis() as u64);
     println!("Total elapsed time: {elapsed_time} msec");
+    stats.print(elapsed_time);
+    if let Some(path) = &json {
+        stats.write_json(path, elapsed_time)?;
+    }
+    if duplicates > 0 {
+        println!("Ignored {duplicates} duplicate results");
+    }
+    if failed > 0 {
+        println!("Got {failed} failed results");
+    }
+    
+    if control_addr.is_some() {
+        control.send(KILL.into()).await?;  // Tell the ventilator and the workers to exit
+        sleep(Duration::from_millis(100)).await;  // Give the message time to get out
+    }
+    
+    if let Some(path) = checkpoint.as_ref().filter(|path| path.exists()) {
+        std::fs::remove_file(path)?;  // Nothing left to resume
+    }
     
     receiver.close().await;
-    Ok(())
+    match wrong {
+        0 => Ok(()),
+        _ => anyhow::bail!("{wrong} batches had wrong results"),
+    }
 }
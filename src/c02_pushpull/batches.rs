@@ -0,0 +1,440 @@
+//! Batches of the `c02_pushpull` pipeline.
+//!
+//! The ventilator sends `--batches` batches of `--batch-size` tasks, one
+//! every `--interval` milliseconds, or batches until it is stopped with
+//! `--continuous`. Before the tasks of a batch, it sends the sink a
+//! `[START, batch, kind, seed, size, last]` message, with what the sink
+//! needs to check the results. The sink tracks the batches that are running
+//! at the same time, and exits once the last one is done.
+//!
+//! A task is only done once its result was aggregated. A worker that failed
+//! to run it, or a result that does not make sense, leaves it to be sent
+//! again, until it failed `MAX_FAILURES` times: the sink then gives up on
+//! it, and its batch is reported as wrong.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use tokio::time::Instant;
+use zeromq::ZmqMessage;
+
+use super::{
+    checkpoint::{Checkpoint, Saved},
+    jobs::{self, Job, Kind},
+    tracker::TaskResult,
+};
+
+/// First frame of the message that starts a batch.
+pub const START: &str = "START";
+/// Failed results of a task after which the sink gives up on it.
+const MAX_FAILURES: u32 = 3;
+/// Results kept for batches that did not start yet. The ventilator sends
+/// the tasks of the results dropped beyond it again.
+const MAX_EARLY: usize = 10_000;
+
+/// Start of a batch, from the ventilator to the sink.
+pub struct Start {
+    pub batch: u64,
+    pub kind: Kind,
+    pub seed: u64,
+    pub size: u64,
+    /// Whether no batch comes after this one.
+    pub last: bool,
+}
+
+impl Start {
+    pub fn encode(&self) -> ZmqMessage {
+        let frames = vec![
+            Bytes::from(START),
+            Bytes::from(self.batch.to_string()),
+            Bytes::from(self.kind.to_string()),
+            Bytes::from(self.seed.to_string()),
+            Bytes::from(self.size.to_string()),
+            Bytes::from(if self.last { "1" } else { "0" }),
+        ];
+        ZmqMessage::try_from(frames).expect("six frames")
+    }
+
+    pub fn decode(frames: &[Bytes]) -> anyhow::Result<Self> {
+        let [_, batch, kind, seed, size, last] = frames else {
+            bail!("expected [START, batch, kind, seed, size, last]");
+        };
+        let text = |frame: &Bytes| String::from_utf8_lossy(frame).to_string();
+        Ok(Self {
+            batch: text(batch).parse()?,
+            kind: Kind::parse(&text(kind))?,
+            seed: text(seed).parse()?,
+            size: text(size).parse()?,
+            last: last.as_ref() == b"1",
+        })
+    }
+}
+
+/// A batch whose results are coming in.
+struct Running {
+    kind: Kind,
+    seed: u64,
+    size: u64,
+    started: Instant,
+    job: Box<dyn Job>,
+    done: BTreeSet<u64>,
+    /// Failed results of the tasks not done yet.
+    failures: BTreeMap<u64, u32>,
+    /// The tasks given up on, with their last error.
+    given_up: BTreeMap<u64, String>,
+    /// The results added so far, for the checkpoints.
+    results: Vec<TaskResult>,
+    /// Whether it started before a checkpoint this sink resumed from.
+    resumed: bool,
+}
+
+/// A batch with all its results.
+pub struct Finished {
+    pub batch: u64,
+    pub kind: Kind,
+    pub size: u64,
+    pub elapsed: Duration,
+    /// Milliseconds the batch should take on a single worker, if known and
+    /// if all of it ran since the sink started.
+    pub expected_cost: Option<u64>,
+    /// The summary of the results, if they are right.
+    pub verified: anyhow::Result<String>,
+}
+
+impl Finished {
+    pub fn throughput(&self) -> f64 {
+        self.size as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+}
+
+/// What a result was to the sink.
+pub enum Added {
+    /// The first result of its task.
+    New,
+    /// A task sent again, but whose first result arrived after all.
+    Duplicate,
+    /// A result that arrived before the start of its batch, kept for then.
+    Early,
+    /// A failed result: the task is left to be sent again.
+    Failed,
+    /// A task that failed too many times: it is done, but without a result.
+    GaveUp,
+    /// A result of no task of its batch, or one too many early results.
+    Rejected,
+}
+
+impl Added {
+    /// Whether the task needs no other result.
+    pub fn is_done(&self) -> bool {
+        matches!(self, Added::New | Added::Duplicate | Added::GaveUp)
+    }
+}
+
+/// The batches seen by the sink.
+#[derive(Default)]
+pub struct Batches {
+    running: BTreeMap<u64, Running>,
+    finished: BTreeSet<u64>,
+    /// Results of batches that did not start yet.
+    early: BTreeMap<u64, Vec<TaskResult>>,
+    last: Option<u64>,
+}
+
+impl Batches {
+    /// Starts a batch, and adds the results that arrived before it.
+    /// Returns the ids of the tasks these results made done.
+    pub fn start(&mut self, start: Start, now: Instant) -> Vec<u64> {
+        if self.running.contains_key(&start.batch) || self.finished.contains(&start.batch) {
+            return Vec::new(); // Already known from a checkpoint
+        }
+        if start.last {
+            self.last = Some(start.batch);
+        }
+        let running = Running {
+            kind: start.kind,
+            seed: start.seed,
+            size: start.size,
+            started: now,
+            job: jobs::job(start.kind, start.seed, start.size),
+            done: BTreeSet::new(),
+            failures: BTreeMap::new(),
+            given_up: BTreeMap::new(),
+            results: Vec::new(),
+            resumed: false,
+        };
+        self.running.insert(start.batch, running);
+        let early = self.early.remove(&start.batch).unwrap_or_default();
+        early
+            .into_iter()
+            .filter_map(|result| {
+                let id = result.id;
+                self.add(result).is_done().then_some(id)
+            })
+            .collect()
+    }
+
+    pub fn add(&mut self, result: TaskResult) -> Added {
+        if self.finished.contains(&result.batch) {
+            return Added::Duplicate;
+        }
+        let Some(running) = self.running.get_mut(&result.batch) else {
+            if self.early.values().map(Vec::len).sum::<usize>() >= MAX_EARLY {
+                eprintln!(
+                    "ERROR: Dropping result {}/{}: too many results of batches that did not start",
+                    result.batch, result.id
+                );
+                return Added::Rejected;
+            }
+            self.early.entry(result.batch).or_default().push(result);
+            return Added::Early;
+        };
+        if result.id >= running.size {
+            eprintln!(
+                "ERROR: Result {}/{} of a batch of {} tasks",
+                result.batch, result.id, running.size
+            );
+            return Added::Rejected;
+        }
+        if running.done.contains(&result.id) {
+            return Added::Duplicate;
+        }
+        let aggregated = match &result.error {
+            Some(error) => Err(anyhow!("{error}")),
+            None => running.job.aggregate(result.id, &result.result),
+        };
+        if let Err(e) = aggregated {
+            let (batch, id) = (result.batch, result.id);
+            eprintln!(
+                "ERROR: Task {batch}/{id} failed at worker {}: {e:#}",
+                result.worker
+            );
+            let failures = running.failures.entry(id).or_default();
+            *failures += 1;
+            if *failures < MAX_FAILURES {
+                return Added::Failed;
+            }
+            eprintln!("ERROR: Giving up on task {batch}/{id} after {MAX_FAILURES} failures");
+            running.failures.remove(&id);
+            running.given_up.insert(id, format!("{e:#}"));
+            running.done.insert(id);
+            return Added::GaveUp;
+        }
+        running.failures.remove(&result.id);
+        running.done.insert(result.id);
+        running.results.push(result);
+        Added::New
+    }
+
+    /// Removes the batches with all their results, and verifies them.
+    pub fn take_finished(&mut self, now: Instant) -> Vec<Finished> {
+        let complete: Vec<u64> = self
+            .running
+            .iter()
+            .filter(|(_, running)| running.done.len() as u64 >= running.size)
+            .map(|(batch, _)| *batch)
+            .collect();
+        complete
+            .into_iter()
+            .filter_map(|batch| {
+                let running = self.running.remove(&batch)?;
+                self.finished.insert(batch);
+                Some(Finished {
+                    batch,
+                    kind: running.kind,
+                    size: running.size,
+                    elapsed: now - running.started,
+                    expected_cost: running.job.expected_cost().filter(|_| !running.resumed),
+                    verified: match running.given_up.first_key_value() {
+                        None => running.job.verify(),
+                        Some((id, error)) => Err(anyhow!(
+                            "{} tasks failed, task {id}: {error}",
+                            running.given_up.len()
+                        )),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// The state to save, without the results of batches that did not start.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let running = self.running.iter().map(|(batch, running)| {
+            let start = Start {
+                batch: *batch,
+                kind: running.kind,
+                seed: running.seed,
+                size: running.size,
+                last: self.last == Some(*batch),
+            };
+            let saved = Saved {
+                start,
+                results: running.results.clone(),
+                failures: running.failures.clone(),
+                given_up: running.given_up.clone(),
+            };
+            (*batch, saved)
+        });
+        Checkpoint {
+            last: self.last,
+            finished: self.finished.clone(),
+            running: running.collect(),
+        }
+    }
+
+    /// Starts again the batches of a checkpoint, with their results.
+    pub fn resume(checkpoint: Checkpoint, now: Instant) -> Self {
+        let mut batches = Self {
+            finished: checkpoint.finished,
+            last: checkpoint.last,
+            ..Self::default()
+        };
+        for saved in checkpoint.running.into_values() {
+            let batch = saved.start.batch;
+            batches.start(saved.start, now);
+            for result in saved.results {
+                batches.add(result);
+            }
+            if let Some(running) = batches.running.get_mut(&batch) {
+                running.resumed = true;
+                running.failures = saved.failures;
+                running.done.extend(saved.given_up.keys());
+                running.given_up = saved.given_up;
+            }
+        }
+        batches
+    }
+
+    /// Whether the last batch and all the ones before it are done.
+    pub fn all_done(&self) -> bool {
+        self.last
+            .is_some_and(|last| (0..=last).all(|batch| self.finished.contains(&batch)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batches(size: u64) -> Batches {
+        let mut batches = Batches::default();
+        let start = Start {
+            batch: 0,
+            kind: Kind::Primes,
+            seed: 0,
+            size,
+            last: true,
+        };
+        batches.start(start, Instant::now());
+        batches
+    }
+
+    fn result(id: u64, result: &str, error: Option<&str>) -> TaskResult {
+        TaskResult {
+            batch: 0,
+            id,
+            worker: "w".to_string(),
+            result: Bytes::from(result.to_string()),
+            busy: 1,
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn failed_results_leave_the_task_to_send_again() {
+        let mut batches = batches(1);
+        let failed = batches.add(result(0, "", Some("out of memory")));
+        assert!(matches!(failed, Added::Failed));
+        let nonsense = batches.add(result(0, "many", None));
+        assert!(matches!(nonsense, Added::Failed));
+        assert!(batches.take_finished(Instant::now()).is_empty());
+
+        assert!(matches!(batches.add(result(0, "1229", None)), Added::New));
+        assert!(matches!(
+            batches.add(result(0, "1229", None)),
+            Added::Duplicate
+        ));
+        let finished = batches.take_finished(Instant::now());
+        assert!(finished[0].verified.is_ok());
+        assert!(batches.all_done());
+    }
+
+    #[test]
+    fn rejects_results_beyond_the_batch() {
+        let mut batches = batches(2);
+        assert!(matches!(
+            batches.add(result(2, "1229", None)),
+            Added::Rejected
+        ));
+        assert!(batches.take_finished(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn caps_the_results_of_batches_that_did_not_start() {
+        let mut batches = Batches::default();
+        for id in 0..MAX_EARLY as u64 {
+            let early = TaskResult {
+                batch: 1,
+                ..result(id, "1229", None)
+            };
+            assert!(matches!(batches.add(early), Added::Early));
+        }
+        let dropped = TaskResult {
+            batch: u64::MAX,
+            ..result(0, "1229", None)
+        };
+        assert!(matches!(batches.add(dropped), Added::Rejected));
+    }
+
+    #[test]
+    fn gives_up_on_a_task_that_keeps_failing() {
+        let mut batches = batches(1);
+        for _ in 1..MAX_FAILURES {
+            assert!(matches!(
+                batches.add(result(0, "", Some("boom"))),
+                Added::Failed
+            ));
+        }
+        assert!(matches!(
+            batches.add(result(0, "", Some("boom"))),
+            Added::GaveUp
+        ));
+        let finished = batches.take_finished(Instant::now());
+        let error = finished[0].verified.as_ref().expect_err("a task failed");
+        assert!(error.to_string().contains("boom"));
+    }
+
+    #[test]
+    fn failures_survive_a_resume() {
+        let mut batches = batches(2);
+        for _ in 0..MAX_FAILURES {
+            batches.add(result(0, "", Some("boom")));
+        }
+        batches.add(result(1, "", Some("late")));
+        let checkpoint = batches.checkpoint();
+        let remaining: Vec<u64> = checkpoint.remaining().iter().map(|task| task.id).collect();
+        assert_eq!(remaining, [1]);
+
+        let mut resumed = Batches::resume(checkpoint, Instant::now());
+        for _ in 1..MAX_FAILURES - 1 {
+            assert!(matches!(
+                resumed.add(result(1, "", Some("late"))),
+                Added::Failed
+            ));
+        }
+        assert!(matches!(
+            resumed.add(result(1, "", Some("late"))),
+            Added::GaveUp
+        ));
+        let finished = resumed.take_finished(Instant::now());
+        let error = finished[0].verified.as_ref().expect_err("tasks failed");
+        assert!(
+            error
+                .to_string()
+                .starts_with("2 tasks failed, task 0: boom")
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Checkpoints of the `c02_pushpull` sink, to resume long jobs after a crash.
+//!
+//! A sink started with `--checkpoint <path>` saves there, every
+//! `--checkpoint-interval` milliseconds, the batches it finished and, for the
+//! others, their start and the results it has: the partial aggregates are
+//! rebuilt by adding these results again. One record per line:
+//! - `last <batch>`: the last batch of the job;
+//! - `finished <batch>`: a batch with all its results;
+//! - `start <batch> <kind> <seed> <size>`: a batch still running;
+//! - `result <batch> <id> <worker> <busy> <result>`: a result of a running
+//!   batch, with the worker id and the result in hex so that no whitespace
+//!   ends up in the record;
+//! - `failures <batch> <id> <count>`: the failed results of a task of a
+//!   running batch that is not done yet;
+//! - `given_up <batch> <id> <error>`: a task of a running batch the sink
+//!   gave up on, with its last error in hex.
+//!
+//! The file is written next to its final path and renamed over it, so a
+//! crash leaves either the previous checkpoint or the new one. A sink
+//! started again with the same path resumes from it, and
+//! `ventilator --resume <path>` only sends the tasks that are not done, with
+//! the same payloads.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    fs::File,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use bytes::Bytes;
+
+use super::{
+    batches::Start,
+    jobs::{self, Kind},
+    tracker::{Task, TaskResult},
+};
+
+/// A batch that was running when the checkpoint was saved.
+pub struct Saved {
+    pub start: Start,
+    pub results: Vec<TaskResult>,
+    /// Failed results of the tasks not done yet.
+    pub failures: BTreeMap<u64, u32>,
+    /// The tasks given up on, with their last error.
+    pub given_up: BTreeMap<u64, String>,
+}
+
+/// What the sink had when it saved the checkpoint.
+#[derive(Default)]
+pub struct Checkpoint {
+    pub last: Option<u64>,
+    pub finished: BTreeSet<u64>,
+    pub running: BTreeMap<u64, Saved>,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading checkpoint {}", path.display()))?;
+        let mut checkpoint = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            checkpoint
+                .parse_line(line)
+                .with_context(|| format!("{}:{}", path.display(), n + 1))?;
+        }
+        Ok(checkpoint)
+    }
+
+    fn parse_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["last", batch] => self.last = Some(batch.parse()?),
+            ["finished", batch] => _ = self.finished.insert(batch.parse()?),
+            ["start", batch, kind, seed, size] => {
+                let start = Start {
+                    batch: batch.parse()?,
+                    kind: Kind::parse(kind)?,
+                    seed: seed.parse()?,
+                    size: size.parse()?,
+                    last: self.last == Some(batch.parse()?),
+                };
+                let saved = Saved {
+                    start,
+                    results: Vec::new(),
+                    failures: BTreeMap::new(),
+                    given_up: BTreeMap::new(),
+                };
+                self.running.insert(saved.start.batch, saved);
+            }
+            ["result", batch, id, worker, busy, hex] => {
+                let batch = batch.parse()?;
+                let saved = self.saved(batch)?;
+                saved.results.push(TaskResult {
+                    batch,
+                    id: id.parse()?,
+                    worker: String::from_utf8(decode_hex(worker)?)?,
+                    result: Bytes::from(decode_hex(hex)?),
+                    busy: busy.parse()?,
+                    error: None,
+                });
+            }
+            ["failures", batch, id, count] => {
+                let saved = self.saved(batch.parse()?)?;
+                saved.failures.insert(id.parse()?, count.parse()?);
+            }
+            ["given_up", batch, id, error] => {
+                let saved = self.saved(batch.parse()?)?;
+                let error = String::from_utf8(decode_hex(error)?)?;
+                saved.given_up.insert(id.parse()?, error);
+            }
+            _ => bail!("unexpected record {line:?}"),
+        }
+        Ok(())
+    }
+
+    /// The running batch a record is about, which must have started.
+    fn saved(&mut self, batch: u64) -> anyhow::Result<&mut Saved> {
+        match self.running.get_mut(&batch) {
+            Some(saved) => Ok(saved),
+            None => bail!("record of batch {batch} before its start"),
+        }
+    }
+
+    /// Writes the checkpoint to a temporary file, then renames it to `path`.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut text = String::new();
+        if let Some(last) = self.last {
+            _ = writeln!(text, "last {last}");
+        }
+        for batch in &self.finished {
+            _ = writeln!(text, "finished {batch}");
+        }
+        for saved in self.running.values() {
+            let start = &saved.start;
+            _ = writeln!(
+                text,
+                "start {} {} {} {}",
+                start.batch, start.kind, start.seed, start.size
+            );
+            for result in &saved.results {
+                _ = writeln!(
+                    text,
+                    "result {} {} {} {} {}",
+                    result.batch,
+                    result.id,
+                    encode_hex(result.worker.as_bytes()),
+                    result.busy,
+                    encode_hex(&result.result)
+                );
+            }
+            for (id, count) in &saved.failures {
+                _ = writeln!(text, "failures {} {id} {count}", start.batch);
+            }
+            for (id, error) in &saved.given_up {
+                let error = encode_hex(error.as_bytes());
+                _ = writeln!(text, "given_up {} {id} {error}", start.batch);
+            }
+        }
+
+        let mut temp = PathBuf::from(path);
+        temp.as_mut_os_string().push(".tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+        // Make the rename itself durable
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+        Ok(())
+    }
+
+    /// The batch after the last one that started.
+    pub fn next_batch(&self) -> u64 {
+        let started = self.finished.iter().chain(self.running.keys());
+        started.max().map_or(0, |batch| batch + 1)
+    }
+
+    /// The tasks of the running batches that are not done, with their payloads.
+    pub fn remaining(&self) -> Vec<Task> {
+        let mut tasks = Vec::new();
+        for saved in self.running.values() {
+            let start = &saved.start;
+            let results = saved.results.iter().map(|result| result.id);
+            let done: BTreeSet<u64> = results.chain(saved.given_up.keys().copied()).collect();
+            let job = jobs::job(start.kind, start.seed, start.size);
+            tasks.extend(
+                (0..start.size)
+                    .filter(|id| !done.contains(id))
+                    .map(|id| Task {
+                        batch: start.batch,
+                        id,
+                        kind: start.kind,
+                        payload: job.payload(id),
+                    }),
+            );
+        }
+        tasks
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    match bytes.is_empty() {
+        true => "-".to_string(),
+        false => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+    }
+}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if hex == "-" {
+        return Ok(Vec::new());
+    }
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd length hex {hex:?}");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_ids_with_whitespace_survive_a_save() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("a temp dir");
+        let path = dir.join("checkpoint");
+        let start = Start {
+            batch: 3,
+            kind: Kind::Sleep,
+            seed: 7,
+            size: 2,
+            last: false,
+        };
+        let result = TaskResult {
+            batch: 3,
+            id: 1,
+            worker: "worker one\tand\ntwo".to_string(),
+            result: Bytes::new(),
+            busy: 12,
+            error: None,
+        };
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.finished.insert(2);
+        checkpoint.running.insert(
+            3,
+            Saved {
+                start,
+                results: vec![result],
+                failures: BTreeMap::from([(0, 2)]),
+                given_up: BTreeMap::new(),
+            },
+        );
+        checkpoint.save(&path).expect("the checkpoint is saved");
+
+        let loaded = Checkpoint::load(&path).expect("the checkpoint loads");
+        std::fs::remove_dir_all(&dir).expect("the temp dir is removed");
+        let results = &loaded.running[&3].results;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].worker, "worker one\tand\ntwo");
+        assert_eq!((results[0].id, results[0].busy), (1, 12));
+        assert!(results[0].result.is_empty());
+        assert_eq!(loaded.finished, BTreeSet::from([2]));
+        assert_eq!(loaded.running[&3].failures, BTreeMap::from([(0, 2)]));
+    }
+}
@@ -0,0 +1,98 @@
+//! Credit-based flow control for the `c02_pushpull` pipeline.
+//!
+//! A PUSH socket sends each task to the next worker in turn, whether it is
+//! busy or not. With `ventilator --credit`, the ventilator binds a ROUTER
+//! socket instead, and workers started with `--credit <n>` connect a DEALER
+//! named after their registration id. Each worker grants `n` credits on the
+//! registration socket when it starts, and one more with each result; the
+//! ventilator only sends a task to a worker with credit left, the one with
+//! the most.
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use zeromq::{SocketOptions, ZmqMessage, ZmqResult, prelude::*};
+
+/// Socket of the ventilator for the tasks.
+pub enum TaskSender {
+    /// Round robin over the workers.
+    Push(zeromq::PushSocket),
+    /// To the worker chosen by the ventilator.
+    Router(zeromq::RouterSocket),
+}
+
+impl TaskSender {
+    pub async fn bind(addr: SocketAddr, credit: bool) -> anyhow::Result<Self> {
+        let endpoint = format!("tcp://{addr}");
+        Ok(match credit {
+            true => {
+                let mut sock = zeromq::RouterSocket::new();
+                sock.bind(endpoint.as_str()).await?;
+                Self::Router(sock)
+            }
+            false => {
+                let mut sock = zeromq::PushSocket::new();
+                sock.bind(endpoint.as_str()).await?;
+                Self::Push(sock)
+            }
+        })
+    }
+
+    /// Sends `task` to `worker` in credit mode, or to the next worker
+    /// without it.
+    pub async fn send(&mut self, worker: Option<&str>, mut task: ZmqMessage) -> ZmqResult<()> {
+        match self {
+            Self::Push(sock) => sock.send(task).await,
+            Self::Router(sock) => {
+                let worker = worker.expect("a worker in credit mode");
+                task.push_front(Bytes::from(worker.to_string()));
+                sock.send(task).await
+            }
+        }
+    }
+
+    pub fn is_credit(&self) -> bool {
+        matches!(self, Self::Router(_))
+    }
+
+    pub async fn close(self) {
+        match self {
+            Self::Push(sock) => _ = sock.close().await,
+            Self::Router(sock) => _ = sock.close().await,
+        }
+    }
+}
+
+/// Socket of a worker for the tasks.
+pub enum TaskReceiver {
+    Pull(zeromq::PullSocket),
+    Dealer(zeromq::DealerSocket),
+}
+
+impl TaskReceiver {
+    /// Connects a DEALER named `worker` in credit mode, a PULL otherwise.
+    pub async fn connect(addr: SocketAddr, worker: &str, credit: bool) -> anyhow::Result<Self> {
+        let endpoint = format!("tcp://{addr}");
+        Ok(match credit {
+            true => {
+                let mut options = SocketOptions::default();
+                options.peer_identity(worker.parse()?);
+                let mut sock = zeromq::DealerSocket::with_options(options);
+                sock.connect(endpoint.as_str()).await?;
+                Self::Dealer(sock)
+            }
+            false => {
+                let mut sock = zeromq::PullSocket::new();
+                sock.connect(endpoint.as_str()).await?;
+                Self::Pull(sock)
+            }
+        })
+    }
+
+    pub async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        match self {
+            Self::Pull(sock) => sock.recv().await,
+            Self::Dealer(sock) => sock.recv().await,
+        }
+    }
+}
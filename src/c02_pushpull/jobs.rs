@@ -0,0 +1,420 @@
+//! Task kinds of the `c02_pushpull` pipeline.
+//!
+//! The ventilator picks a kind with `--job` and a random seed, and tells the
+//! sink both at the start of the batch. Every payload is generated from the
+//! seed and the task id, so the sink can generate them again to check the
+//! results it aggregated:
+//! - `sleep`: sleep for a number of milliseconds and return nothing;
+//! - `primes`: count the primes in a range, checked against a sieve;
+//! - `hash`: SHA-256 of a chunk of random bytes, all of them hashed again;
+//! - `matrix`: a block of rows of a matrix product, checked with Freivalds' algorithm;
+//! - `wordcount`: count the words of a text shard, checked by counting them again.
+
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use anyhow::{Context, anyhow, bail};
+use bytes::Bytes;
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use sha2::{Digest, Sha256};
+
+/// Numbers in the range of each `primes` task.
+const PRIMES_RANGE: u64 = 10_000;
+/// Bytes in each `hash` chunk.
+const HASH_CHUNK: usize = 64 * 1024;
+/// Rows of the left matrix in each `matrix` task, and size of the right matrix.
+const MATRIX_BLOCK: usize = 32;
+/// Words in each `wordcount` shard.
+const SHARD_WORDS: usize = 2000;
+const VOCABULARY: &[&str] = &[
+    "the", "of", "and", "to", "in", "socket", "message", "broker", "worker", "sink", "queue",
+    "pipeline", "task", "result", "frame", "topic",
+];
+
+/// Kind of the tasks of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    Sleep,
+    Primes,
+    Hash,
+    Matrix,
+    Wordcount,
+}
+
+impl Kind {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        Self::from_str(name, false).map_err(|e| anyhow!("unknown task kind: {e}"))
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// The tasks of a batch, on the ventilator and on the sink.
+pub trait Job: Send {
+    /// Payload of task `id`.
+    fn payload(&self, id: u64) -> Bytes;
+    /// Milliseconds the whole batch should take on a single worker, if known.
+    fn expected_cost(&self) -> Option<u64> {
+        None
+    }
+    /// Adds the result of task `id`; called once for each task.
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()>;
+    /// Checks the aggregated results, and sums them up if they are right.
+    fn verify(&self) -> anyhow::Result<String>;
+}
+
+/// The batch of `tasks` tasks of this kind generated from `seed`.
+pub fn job(kind: Kind, seed: u64, tasks: u64) -> Box<dyn Job> {
+    match kind {
+        Kind::Sleep => Box::new(Sleep {
+            seed,
+            tasks,
+            slept: 0,
+        }),
+        Kind::Primes => Box::new(Primes { tasks, count: 0 }),
+        Kind::Hash => Box::new(Hash {
+            seed,
+            tasks,
+            digests: BTreeMap::new(),
+        }),
+        Kind::Matrix => Box::new(Matrix {
+            seed,
+            tasks,
+            blocks: BTreeMap::new(),
+        }),
+        Kind::Wordcount => Box::new(WordCount {
+            seed,
+            tasks,
+            counts: BTreeMap::new(),
+        }),
+    }
+}
+
+/// Computes the result of a task; run by the workers.
+pub fn run(kind: Kind, payload: &[u8]) -> anyhow::Result<Bytes> {
+    let text = std::str::from_utf8(payload);
+    match kind {
+        Kind::Sleep => {
+            std::thread::sleep(Duration::from_millis(text?.trim().parse()?)); // Faking a long computation
+            Ok(Bytes::new())
+        }
+        Kind::Primes => {
+            let (lo, hi) = text?.split_once(' ').context("expected `lo hi`")?;
+            let (lo, hi): (u64, u64) = (lo.parse()?, hi.parse()?);
+            let count = (lo..hi).filter(|&n| is_prime(n)).count();
+            Ok(Bytes::from(count.to_string()))
+        }
+        Kind::Hash => Ok(Bytes::from(hex_sha256(payload))),
+        Kind::Matrix => {
+            let (a, b) = text?.split_once("\n\n").context("expected two matrices")?;
+            let product = multiply(&parse_matrix(a)?, &parse_matrix(b)?)?;
+            Ok(Bytes::from(format_matrix(&product)))
+        }
+        Kind::Wordcount => {
+            let counts = count_words(text?);
+            let lines: String = counts.iter().map(|(w, n)| format!("{w} {n}\n")).collect();
+            Ok(Bytes::from(lines))
+        }
+    }
+}
+
+/// Generator of the payload of task `id`.
+fn rng(seed: u64, id: u64) -> StdRng {
+    StdRng::seed_from_u64(seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Sleep for 1 to 99 milliseconds.
+struct Sleep {
+    seed: u64,
+    tasks: u64,
+    slept: u64,
+}
+
+impl Sleep {
+    fn workload(&self, id: u64) -> u64 {
+        rng(self.seed, id).random_range(1..100)
+    }
+}
+
+impl Job for Sleep {
+    fn payload(&self, id: u64) -> Bytes {
+        Bytes::from(self.workload(id).to_string())
+    }
+
+    fn expected_cost(&self) -> Option<u64> {
+        Some((0..self.tasks).map(|id| self.workload(id)).sum())
+    }
+
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()> {
+        if !result.is_empty() {
+            bail!("task {id}: a sleep has no result");
+        }
+        self.slept += self.workload(id);
+        Ok(())
+    }
+
+    fn verify(&self) -> anyhow::Result<String> {
+        Ok(format!("{} tasks slept {} msec", self.tasks, self.slept))
+    }
+}
+
+fn is_prime(n: u64) -> bool {
+    match n {
+        0 | 1 => false,
+        2 | 3 => true,
+        _ if n.is_multiple_of(2) || n.is_multiple_of(3) => false,
+        _ => (5..)
+            .step_by(6)
+            .take_while(|i| i * i <= n)
+            .all(|i| !n.is_multiple_of(i) && !n.is_multiple_of(i + 2)),
+    }
+}
+
+/// Count the primes below `tasks * PRIMES_RANGE`, one range each.
+struct Primes {
+    tasks: u64,
+    count: u64,
+}
+
+impl Job for Primes {
+    fn payload(&self, id: u64) -> Bytes {
+        Bytes::from(format!("{} {}", id * PRIMES_RANGE, (id + 1) * PRIMES_RANGE))
+    }
+
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()> {
+        let count: u64 = std::str::from_utf8(result)?
+            .parse()
+            .with_context(|| format!("task {id}: expected a count"))?;
+        self.count += count;
+        Ok(())
+    }
+
+    fn verify(&self) -> anyhow::Result<String> {
+        let limit = (self.tasks * PRIMES_RANGE) as usize;
+        let mut sieve = vec![true; limit];
+        sieve.iter_mut().take(2).for_each(|n| *n = false);
+        for i in (2..).take_while(|i| i * i < limit) {
+            if sieve[i] {
+                (i * i..limit).step_by(i).for_each(|j| sieve[j] = false);
+            }
+        }
+        let expected = sieve.iter().filter(|&&prime| prime).count() as u64;
+        if self.count != expected {
+            bail!(
+                "counted {} primes below {limit}, expected {expected}",
+                self.count
+            );
+        }
+        Ok(format!("{} primes below {limit}", self.count))
+    }
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Hash chunks of random bytes.
+struct Hash {
+    seed: u64,
+    tasks: u64,
+    digests: BTreeMap<u64, String>,
+}
+
+impl Job for Hash {
+    fn payload(&self, id: u64) -> Bytes {
+        let mut chunk = vec![0; HASH_CHUNK];
+        rng(self.seed, id).fill(chunk.as_mut_slice());
+        Bytes::from(chunk)
+    }
+
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()> {
+        self.digests.insert(id, String::from_utf8(result.to_vec())?);
+        Ok(())
+    }
+
+    fn verify(&self) -> anyhow::Result<String> {
+        for id in 0..self.tasks {
+            match self.digests.get(&id) {
+                None => bail!("no digest for chunk {id}"),
+                Some(digest) if *digest != hex_sha256(&self.payload(id)) => {
+                    bail!("wrong digest for chunk {id}")
+                }
+                Some(_) => {}
+            }
+        }
+        let all: String = self.digests.values().map(String::as_str).collect();
+        Ok(format!(
+            "{} chunks hashed and checked, digest of the digests {}",
+            self.digests.len(),
+            &hex_sha256(all.as_bytes())[..16]
+        ))
+    }
+}
+
+type Matrix64 = Vec<Vec<i64>>;
+
+fn random_matrix(rng: &mut StdRng, rows: usize, cols: usize) -> Matrix64 {
+    (0..rows)
+        .map(|_| (0..cols).map(|_| rng.random_range(-9..10)).collect())
+        .collect()
+}
+
+fn parse_matrix(text: &str) -> anyhow::Result<Matrix64> {
+    text.lines()
+        .map(|row| row.split_whitespace().map(|v| Ok(v.parse()?)).collect())
+        .collect()
+}
+
+fn format_matrix(matrix: &Matrix64) -> String {
+    matrix
+        .iter()
+        .map(|row| row.iter().map(i64::to_string).collect::<Vec<_>>().join(" ") + "\n")
+        .collect()
+}
+
+fn multiply(a: &Matrix64, b: &Matrix64) -> anyhow::Result<Matrix64> {
+    if a.iter().any(|row| row.len() != b.len()) {
+        bail!("cannot multiply these matrices");
+    }
+    let cols = b.first().map_or(0, Vec::len);
+    Ok(a.iter()
+        .map(|row| {
+            (0..cols)
+                .map(|j| row.iter().zip(b).map(|(x, b_row)| x * b_row[j]).sum())
+                .collect()
+        })
+        .collect())
+}
+
+/// Multiply a tall matrix `A` by a square matrix `B`, one block of rows of
+/// `A` in each task.
+struct Matrix {
+    seed: u64,
+    tasks: u64,
+    blocks: BTreeMap<u64, Matrix64>,
+}
+
+impl Matrix {
+    fn a_block(&self, id: u64) -> Matrix64 {
+        random_matrix(&mut rng(self.seed, id), MATRIX_BLOCK, MATRIX_BLOCK)
+    }
+
+    fn b(&self) -> Matrix64 {
+        random_matrix(&mut rng(self.seed, u64::MAX), MATRIX_BLOCK, MATRIX_BLOCK)
+    }
+}
+
+impl Job for Matrix {
+    fn payload(&self, id: u64) -> Bytes {
+        let a = format_matrix(&self.a_block(id));
+        Bytes::from(format!("{a}\n{}", format_matrix(&self.b())))
+    }
+
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()> {
+        let block = parse_matrix(std::str::from_utf8(result)?)?;
+        if block.len() != MATRIX_BLOCK || block.iter().any(|row| row.len() != MATRIX_BLOCK) {
+            bail!("task {id}: expected a {MATRIX_BLOCK}x{MATRIX_BLOCK} block");
+        }
+        self.blocks.insert(id, block);
+        Ok(())
+    }
+
+    /// Freivalds: for a random vector `x`, `C x` must equal `A (B x)`.
+    fn verify(&self) -> anyhow::Result<String> {
+        if self.blocks.len() as u64 != self.tasks {
+            bail!("{} blocks for {} tasks", self.blocks.len(), self.tasks);
+        }
+        for (&id, block) in &self.blocks {
+            if id >= self.tasks {
+                bail!("block {id} of {} tasks", self.tasks);
+            }
+            if block.len() != MATRIX_BLOCK || block.iter().any(|row| row.len() != MATRIX_BLOCK) {
+                bail!("block {id} is not {MATRIX_BLOCK}x{MATRIX_BLOCK}");
+            }
+        }
+        let column = |v: Vec<i64>| v.into_iter().map(|x| vec![x]).collect::<Matrix64>();
+        let x = column(
+            (0..MATRIX_BLOCK)
+                .map(|_| rand::rng().random_range(0..2))
+                .collect(),
+        );
+        let bx = multiply(&self.b(), &x)?;
+        for (&id, block) in &self.blocks {
+            if multiply(block, &x)? != multiply(&self.a_block(id), &bx)? {
+                bail!("wrong product for the rows of block {id}");
+            }
+        }
+        Ok(format!(
+            "{}x{MATRIX_BLOCK} product passed Freivalds' check",
+            self.tasks as usize * MATRIX_BLOCK
+        ))
+    }
+}
+
+fn count_words(text: &str) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word.to_lowercase()).or_default() += 1;
+    }
+    counts
+}
+
+/// Count the words of shards of random text.
+struct WordCount {
+    seed: u64,
+    tasks: u64,
+    counts: BTreeMap<String, u64>,
+}
+
+impl Job for WordCount {
+    fn payload(&self, id: u64) -> Bytes {
+        let mut rng = rng(self.seed, id);
+        let words: Vec<&str> = (0..SHARD_WORDS)
+            .map(|_| *VOCABULARY.choose(&mut rng).expect("not empty"))
+            .collect();
+        Bytes::from(words.join(" "))
+    }
+
+    fn aggregate(&mut self, id: u64, result: &[u8]) -> anyhow::Result<()> {
+        for line in std::str::from_utf8(result)?.lines() {
+            let (word, count) = line
+                .split_once(' ')
+                .with_context(|| format!("task {id}: expected `word count` lines"))?;
+            *self.counts.entry(word.to_string()).or_default() += count.parse::<u64>()?;
+        }
+        Ok(())
+    }
+
+    fn verify(&self) -> anyhow::Result<String> {
+        let mut expected: BTreeMap<String, u64> = BTreeMap::new();
+        for id in 0..self.tasks {
+            let shard = self.payload(id);
+            for (word, count) in count_words(std::str::from_utf8(&shard)?) {
+                *expected.entry(word).or_default() += count;
+            }
+        }
+        if self.counts != expected {
+            bail!("the word counts differ from the text");
+        }
+        let mut common: Vec<(&String, &u64)> = self.counts.iter().collect();
+        common.sort_by(|a, b| b.1.cmp(a.1));
+        let top: Vec<String> = common
+            .iter()
+            .take(3)
+            .map(|(w, n)| format!("{w} ({n})"))
+            .collect();
+        Ok(format!(
+            "{} words, most common: {}",
+            self.counts.values().sum::<u64>(),
+            top.join(", ")
+        ))
+    }
+}
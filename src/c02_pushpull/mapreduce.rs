@@ -0,0 +1,893 @@
+//! A small MapReduce engine on the `c02_pushpull` pipeline.
+//!
+//! ```text
+//! ventilator --map tasks--> workers --partitions--> shuffle (in the ventilator)
+//! shuffle --reduce tasks--> workers --output--> sink --> part-00000, part-00001, ...
+//! ```
+//!
+//! The ventilator splits the input files into map tasks of `--chunk-lines`
+//! lines. Workers run the mapper of the job on them and split the pairs it
+//! emits into one partition per reducer, by the hash of the key. Once every
+//! map task sent its partitions, the shuffle stage of the ventilator gathers
+//! each partition into a reduce task. Workers run the reducer on each key of
+//! it, and the sink writes one output file for each partition.
+//!
+//! Map tasks are tracked as batch 0 and reduce tasks as batch 1 (see
+//! `tracker`): a map task is done once all its partitions arrived, and a
+//! reduce task once the sink publishes `DONE` for its partition on the
+//! control socket. Tasks not done after the task timeout are sent again.
+//!
+//! Jobs are registered in [`JOBS`]; `wordcount` and `index` are built in.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow, bail};
+use bytes::Bytes;
+use tokio::time::{Instant, interval};
+use zeromq::{ZmqMessage, prelude::*};
+
+use super::{
+    KILL, TIMEOUT_CHECK, control_recv, control_socket, pull_recv,
+    registry::{Registration, Registry, worker_id},
+    tracker::{DONE, Tracked, Tracker},
+};
+
+/// A MapReduce job.
+pub trait MapReduce: Send + Sync {
+    /// Emits the `(key, value)` pairs of some lines of the document `doc`.
+    fn map(&self, doc: &str, text: &str, emit: &mut dyn FnMut(String, String));
+    /// Reduces all the values emitted for `key`.
+    fn reduce(&self, key: &str, values: Vec<String>) -> String;
+}
+
+/// The jobs that can be run, by name.
+pub const JOBS: &[(&str, &dyn MapReduce)] = &[("wordcount", &WordCount), ("index", &Index)];
+
+/// The most reducers a job may have, also for counts read from the network.
+pub const MAX_REDUCERS: usize = 1024;
+
+fn registered(name: &str) -> anyhow::Result<&'static dyn MapReduce> {
+    JOBS.iter()
+        .find(|(job, _)| *job == name)
+        .map(|(_, job)| *job)
+        .ok_or_else(|| {
+            let known: Vec<&str> = JOBS.iter().map(|(job, _)| *job).collect();
+            anyhow!("unknown job `{name}`, expected one of {}", known.join(", "))
+        })
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Number of times each word appears.
+struct WordCount;
+
+impl MapReduce for WordCount {
+    fn map(&self, _: &str, text: &str, emit: &mut dyn FnMut(String, String)) {
+        words(text).for_each(|word| emit(word, "1".to_string()));
+    }
+
+    fn reduce(&self, _: &str, values: Vec<String>) -> String {
+        let count: u64 = values.iter().filter_map(|v| v.parse::<u64>().ok()).sum();
+        count.to_string()
+    }
+}
+
+/// Documents each word appears in.
+struct Index;
+
+impl MapReduce for Index {
+    fn map(&self, doc: &str, text: &str, emit: &mut dyn FnMut(String, String)) {
+        let unique: BTreeSet<String> = words(text).collect();
+        unique
+            .into_iter()
+            .for_each(|word| emit(word, doc.to_string()));
+    }
+
+    /// The documents joined by commas, with `\` before the commas and
+    /// backslashes of their paths.
+    fn reduce(&self, _: &str, values: Vec<String>) -> String {
+        let docs: BTreeSet<String> = values.into_iter().collect();
+        let docs: Vec<String> = docs
+            .iter()
+            .map(|doc| doc.replace('\\', "\\\\").replace(',', "\\,"))
+            .collect();
+        docs.join(",")
+    }
+}
+
+/// FNV-1a, so that every worker puts a key in the same partition.
+fn partition(key: &str, reducers: usize) -> usize {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    (hash % reducers as u64) as usize
+}
+
+/// Escapes the backslashes, tabs and line breaks of a key or value.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Pairs as `key\tvalue` lines, with the keys and values escaped.
+fn encode_pairs<'a>(pairs: impl Iterator<Item = (&'a String, &'a String)>) -> Bytes {
+    let lines: String = pairs
+        .map(|(key, value)| format!("{}\t{}\n", escape(key), escape(value)))
+        .collect();
+    Bytes::from(lines)
+}
+
+fn decode_pairs(frame: &[u8]) -> anyhow::Result<impl Iterator<Item = (String, String)>> {
+    let lines = std::str::from_utf8(frame).context("pairs that are not UTF-8")?;
+    Ok(lines
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(key, value)| (unescape(key), unescape(value))))
+}
+
+fn message(frames: Vec<Bytes>) -> ZmqMessage {
+    ZmqMessage::try_from(frames).expect("at least one frame")
+}
+
+fn text(frame: &[u8]) -> String {
+    String::from_utf8_lossy(frame).to_string()
+}
+
+/// A number of reducers from the network, within the ones a job may have.
+fn parse_reducers(frame: &[u8]) -> anyhow::Result<usize> {
+    let reducers: usize = text(frame).parse()?;
+    if !(1..=MAX_REDUCERS).contains(&reducers) {
+        bail!("{reducers} reducers, expected 1 to {MAX_REDUCERS}");
+    }
+    Ok(reducers)
+}
+
+/// A task of the workers.
+enum Task {
+    /// `[map, job, id, reducers, doc, text]`
+    Map {
+        job: String,
+        id: usize,
+        reducers: usize,
+        doc: String,
+        text: String,
+    },
+    /// `[reduce, job, partition, pairs]`
+    Reduce {
+        job: String,
+        partition: usize,
+        pairs: Bytes,
+    },
+}
+
+impl Tracked for Task {
+    fn key(&self) -> (u64, u64) {
+        match self {
+            Task::Map { id, .. } => (0, *id as u64),
+            Task::Reduce { partition, .. } => (1, *partition as u64),
+        }
+    }
+}
+
+impl Task {
+    fn job(&self) -> &str {
+        match self {
+            Task::Map { job, .. } | Task::Reduce { job, .. } => job,
+        }
+    }
+
+    fn encode(&self) -> ZmqMessage {
+        message(match self {
+            Task::Map {
+                job,
+                id,
+                reducers,
+                doc,
+                text,
+            } => vec![
+                Bytes::from("map"),
+                Bytes::from(job.clone()),
+                Bytes::from(id.to_string()),
+                Bytes::from(reducers.to_string()),
+                Bytes::from(doc.clone()),
+                Bytes::from(text.clone()),
+            ],
+            Task::Reduce {
+                job,
+                partition,
+                pairs,
+            } => vec![
+                Bytes::from("reduce"),
+                Bytes::from(job.clone()),
+                Bytes::from(partition.to_string()),
+                pairs.clone(),
+            ],
+        })
+    }
+
+    fn decode(msg: ZmqMessage) -> anyhow::Result<Self> {
+        let frames = msg.into_vec();
+        Ok(match frames.as_slice() {
+            [kind, job, id, reducers, doc, body] if kind.as_ref() == b"map" => Task::Map {
+                job: text(job),
+                id: text(id).parse()?,
+                reducers: parse_reducers(reducers)?,
+                doc: text(doc),
+                text: text(body),
+            },
+            [kind, job, partition, pairs] if kind.as_ref() == b"reduce" => Task::Reduce {
+                job: text(job),
+                partition: text(partition).parse()?,
+                pairs: pairs.clone(),
+            },
+            _ => bail!("malformed task of {} frames", frames.len()),
+        })
+    }
+}
+
+/// Splits the input files into map tasks of `chunk_lines` lines.
+fn split(
+    job: &str,
+    inputs: &[PathBuf],
+    chunk_lines: usize,
+    reducers: usize,
+) -> anyhow::Result<Vec<Task>> {
+    let mut tasks = Vec::new();
+    for input in inputs {
+        let contents = std::fs::read_to_string(input)
+            .with_context(|| format!("reading {}", input.display()))?;
+        let lines: Vec<&str> = contents.lines().collect();
+        for chunk in lines.chunks(chunk_lines.max(1)) {
+            tasks.push(Task::Map {
+                job: job.to_string(),
+                id: tasks.len(),
+                reducers,
+                doc: input.display().to_string(),
+                text: chunk.join("\n"),
+            });
+        }
+    }
+    Ok(tasks)
+}
+
+/// The shuffle stage: gathers the partitions of every map task, and turns
+/// each partition into a reduce task once all of them arrived.
+struct Shuffle {
+    maps: usize,
+    /// Map tasks that sent each partition, and the pairs they sent.
+    partitions: Vec<BTreeMap<usize, Bytes>>,
+}
+
+impl Shuffle {
+    fn new(maps: usize, reducers: usize) -> Self {
+        Self {
+            maps,
+            partitions: vec![BTreeMap::new(); reducers],
+        }
+    }
+
+    /// Adds `[map id, partition, pairs]` from a worker, and returns the
+    /// map id. A map task sent twice only counts once.
+    fn add(&mut self, msg: ZmqMessage) -> anyhow::Result<usize> {
+        let frames = msg.into_vec();
+        let [id, partition, pairs] = frames.as_slice() else {
+            bail!("malformed partition of {} frames", frames.len());
+        };
+        let partition: usize = text(partition).parse()?;
+        let maps = self
+            .partitions
+            .get_mut(partition)
+            .ok_or(anyhow!("no partition {partition}"))?;
+        let id = text(id).parse()?;
+        maps.insert(id, pairs.clone());
+        Ok(id)
+    }
+
+    /// Whether all the partitions of map task `id` arrived.
+    fn mapped(&self, id: usize) -> bool {
+        self.partitions.iter().all(|maps| maps.contains_key(&id))
+    }
+
+    fn is_done(&self) -> bool {
+        self.partitions.iter().all(|maps| maps.len() == self.maps)
+    }
+
+    fn reduce_tasks(&mut self, job: &str) -> Vec<Task> {
+        std::mem::take(&mut self.partitions)
+            .into_iter()
+            .enumerate()
+            .map(|(partition, maps)| Task::Reduce {
+                job: job.to_string(),
+                partition,
+                pairs: maps.into_values().flatten().collect::<Vec<u8>>().into(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Role {
+    /// Split the input files into map tasks and shuffle their output to
+    /// the reducers. Workers register on the register addr.
+    Ventilator {
+        sender: SocketAddr,
+        shuffle: SocketAddr,
+        sink: SocketAddr,
+        register: SocketAddr,
+        /// Job to run.
+        #[arg(long, default_value = "wordcount")]
+        job: String,
+        /// Number of reduce tasks, and of output files.
+        #[arg(long, default_value_t = 2)]
+        reducers: usize,
+        /// Lines of input in each map task.
+        #[arg(long, default_value_t = 1000)]
+        chunk_lines: usize,
+        /// Number of workers to wait for before sending the tasks.
+        #[arg(long, default_value_t = 1)]
+        min_workers: usize,
+        /// Start anyway after this many seconds, with the workers registered by then.
+        #[arg(long)]
+        wait: Option<u64>,
+        /// Wait for the KILL message of the sink on this addr before exiting,
+        /// and send again the reduce tasks the sink did not report as done in time.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+        /// Milliseconds to wait for a task to be done before sending it again.
+        #[arg(long, default_value_t = 2000)]
+        task_timeout: u64,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Run map and reduce tasks, specifying the ventilator, shuffle, sink
+    /// and registration addresses.
+    Worker {
+        receiver: SocketAddr,
+        shuffle: SocketAddr,
+        sink: SocketAddr,
+        register: SocketAddr,
+        /// Exit when the sink publishes KILL on this addr.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+    },
+    /// Write the output of the reducers into a directory.
+    Sink {
+        receiver: SocketAddr,
+        /// Directory of the output files.
+        #[arg(long)]
+        output: PathBuf,
+        /// Publish KILL on a PUB socket bound here once the job is done.
+        #[arg(long)]
+        control: Option<SocketAddr>,
+    },
+}
+
+/// What the ventilator runs, and when it starts.
+pub struct Plan {
+    pub job: String,
+    pub inputs: Vec<PathBuf>,
+    pub reducers: usize,
+    pub chunk_lines: usize,
+    pub min_workers: usize,
+    pub wait: Option<Duration>,
+    pub task_timeout: Duration,
+}
+
+pub async fn main(role: Role) -> anyhow::Result<()> {
+    match role {
+        Role::Ventilator {
+            sender,
+            shuffle,
+            sink,
+            register,
+            job,
+            reducers,
+            chunk_lines,
+            min_workers,
+            wait,
+            control,
+            task_timeout,
+            inputs,
+        } => {
+            let plan = Plan {
+                job,
+                inputs,
+                reducers,
+                chunk_lines,
+                min_workers,
+                wait: wait.map(Duration::from_secs),
+                task_timeout: Duration::from_millis(task_timeout),
+            };
+            ventilator_handler(sender, shuffle, sink, register, control, plan).await
+        }
+        Role::Worker {
+            receiver,
+            shuffle,
+            sink,
+            register,
+            control,
+        } => worker_handler(receiver, shuffle, sink, register, control).await,
+        Role::Sink {
+            receiver,
+            output,
+            control,
+        } => sink_handler(receiver, &output, control).await,
+    }
+}
+
+/// Sends the tasks of `queue` that the registered workers can take now.
+async fn send_tasks(
+    sender: &mut zeromq::PushSocket,
+    registry: &mut Registry,
+    tracker: &mut Tracker<Task>,
+    queue: &mut VecDeque<Task>,
+) {
+    while !queue.is_empty() {
+        if !registry.take_ready() {
+            break;
+        }
+        let task = queue.pop_front().expect("not empty");
+        if let Err(e) = sender.send(task.encode()).await {
+            // The worker it went to is gone: try again on the next event
+            eprintln!("Error sending a task: {e}");
+            queue.push_front(task);
+            registry.refund_ready();
+            break;
+        }
+        tracker.sent(task, Instant::now());
+    }
+}
+
+pub async fn ventilator_handler(
+    sender_addr: SocketAddr,
+    shuffle_addr: SocketAddr,
+    sink_addr: SocketAddr,
+    register_addr: SocketAddr,
+    control_addr: Option<SocketAddr>,
+    plan: Plan,
+) -> anyhow::Result<()> {
+    registered(&plan.job)?;
+    if !(1..=MAX_REDUCERS).contains(&plan.reducers) {
+        bail!("a job needs 1 to {MAX_REDUCERS} reducers");
+    }
+    let maps = split(&plan.job, &plan.inputs, plan.chunk_lines, plan.reducers)?;
+
+    let mut registry = Registry::bind(register_addr, false).await?;
+    let mut sender = zeromq::PushSocket::new();
+    sender.bind(format!("tcp://{sender_addr}").as_str()).await?;
+    let mut shuffle_sock = zeromq::PullSocket::new();
+    shuffle_sock
+        .bind(format!("tcp://{shuffle_addr}").as_str())
+        .await?;
+    let mut sink = zeromq::PushSocket::new();
+    sink.connect(format!("tcp://{sink_addr}").as_str()).await?;
+    let mut control = control_socket(control_addr, &[KILL, DONE]).await?;
+
+    println!("Waiting for {} workers to register...", plan.min_workers);
+    let workers = registry.wait_for(plan.min_workers, plan.wait).await?;
+    println!(
+        "Running {} on {workers} workers: {} map tasks, {} reduce tasks",
+        plan.job,
+        maps.len(),
+        plan.reducers
+    );
+    let start = vec![
+        Bytes::from(plan.job.clone()),
+        Bytes::from(plan.reducers.to_string()),
+    ];
+    sink.send(message(start)).await?;
+
+    let mut shuffle = Shuffle::new(maps.len(), plan.reducers);
+    let mut queue: VecDeque<Task> = maps.into();
+    let mut reducing = false;
+    let mut tracker = Tracker::new(plan.task_timeout);
+    let mut timeout_check = interval(TIMEOUT_CHECK);
+    loop {
+        send_tasks(&mut sender, &mut registry, &mut tracker, &mut queue).await;
+        if !reducing && shuffle.is_done() {
+            println!("Map done, shuffling {} partitions", plan.reducers);
+            queue.extend(shuffle.reduce_tasks(&plan.job));
+            reducing = true;
+            continue;
+        }
+        if reducing && queue.is_empty() && control.is_none() {
+            break; // Nobody tells us when the job is done
+        }
+        tokio::select! {
+            taken = registry.recv() => {
+                if let Some(taken) = taken? {
+                    tracker.taken(taken.batch, taken.task, taken.worker);
+                }
+            }
+            partition = pull_recv(&mut shuffle_sock) => {
+                match shuffle.add(partition?) {
+                    Ok(id) if shuffle.mapped(id) => tracker.done(0, id as u64),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("ERROR: {e:#}"),
+                }
+            }
+            msg = control_recv(&mut control) => {
+                let msg = msg?;
+                let words: Vec<&str> = msg.split_whitespace().collect();
+                match words.as_slice() {
+                    [DONE, batch, id] => match (batch.parse(), id.parse()) {
+                        (Ok(batch), Ok(id)) => tracker.done(batch, id),
+                        _ => eprintln!("ERROR: Malformed control message {msg:?}"),
+                    },
+                    [KILL] => break,
+                    _ => eprintln!("ERROR: Unexpected control message {msg:?}"),
+                }
+            }
+            _ = timeout_check.tick() => {
+                queue.extend(tracker.expired(Instant::now()));
+            }
+        }
+    }
+    println!("Job done, exiting");
+    tracker.report();
+
+    sink.close().await;
+    sender.close().await;
+    Ok(())
+}
+
+pub async fn worker_handler(
+    receiver_addr: SocketAddr,
+    shuffle_addr: SocketAddr,
+    sink_addr: SocketAddr,
+    register_addr: SocketAddr,
+    control_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut receiver = zeromq::PullSocket::new();
+    receiver
+        .connect(format!("tcp://{receiver_addr}").as_str())
+        .await?;
+    let mut shuffle = zeromq::PushSocket::new();
+    shuffle
+        .connect(format!("tcp://{shuffle_addr}").as_str())
+        .await?;
+    let mut sink = zeromq::PushSocket::new();
+    sink.connect(format!("tcp://{sink_addr}").as_str()).await?;
+    let mut control = control_socket(control_addr, &[KILL]).await?;
+    let mut registration = Registration::connect(register_addr, worker_id(), 1).await?;
+    println!("Registered as worker {}", registration.id());
+
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.recv() => msg,
+            msg = control_recv(&mut control) => {
+                msg?;
+                println!("Job done, exiting");
+                return Ok(());
+            }
+        };
+        let task = msg.map_err(anyhow::Error::from).and_then(Task::decode);
+        let (job, task) = match task.and_then(|task| Ok((registered(task.job())?, task))) {
+            Ok(task) => task,
+            Err(e) => {
+                // Skip the task, but still take the next one
+                eprintln!("ERROR: {e:#}");
+                registration.ready().await?;
+                continue;
+            }
+        };
+        let (batch, id) = task.key();
+        registration.taken(batch, id).await?;
+        match task {
+            Task::Map {
+                id,
+                reducers,
+                doc,
+                text,
+                ..
+            } => {
+                println!("Mapping task {id} ({doc})");
+                let mut partitions = vec![BTreeMap::<String, Vec<String>>::new(); reducers];
+                job.map(&doc, &text, &mut |key, value| {
+                    let partition = &mut partitions[partition(&key, reducers)];
+                    partition.entry(key).or_default().push(value);
+                });
+                for (partition, pairs) in partitions.iter().enumerate() {
+                    let pairs = pairs
+                        .iter()
+                        .flat_map(|(key, values)| values.iter().map(move |value| (key, value)));
+                    let frames = vec![
+                        Bytes::from(id.to_string()),
+                        Bytes::from(partition.to_string()),
+                        encode_pairs(pairs),
+                    ];
+                    shuffle.send(message(frames)).await?;
+                }
+            }
+            Task::Reduce {
+                partition, pairs, ..
+            } => {
+                println!("Reducing partition {partition}");
+                let pairs = match decode_pairs(&pairs) {
+                    Ok(pairs) => pairs,
+                    Err(e) => {
+                        eprintln!("ERROR: Partition {partition}: {e:#}");
+                        registration.ready().await?;
+                        continue;
+                    }
+                };
+                let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for (key, value) in pairs {
+                    grouped.entry(key).or_default().push(value);
+                }
+                let reduced: Vec<(String, String)> = grouped
+                    .into_iter()
+                    .map(|(key, values)| {
+                        let value = job.reduce(&key, values);
+                        (key, value)
+                    })
+                    .collect();
+                let frames = vec![
+                    Bytes::from(partition.to_string()),
+                    Bytes::from(registration.id().to_string()),
+                    encode_pairs(reduced.iter().map(|(key, value)| (key, value))),
+                ];
+                sink.send(message(frames)).await?;
+            }
+        }
+        registration.ready().await?;
+    }
+}
+
+pub async fn sink_handler(
+    receiver_addr: SocketAddr,
+    output: &Path,
+    control_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut receiver = zeromq::PullSocket::new();
+    receiver
+        .bind(format!("tcp://{receiver_addr}").as_str())
+        .await?;
+    let mut control = zeromq::PubSocket::new();
+    if let Some(control_addr) = control_addr {
+        control
+            .bind(format!("tcp://{control_addr}").as_str())
+            .await?;
+    }
+
+    let start = receiver.recv().await?.into_vec();
+    let [job, reducers] = start.as_slice() else {
+        bail!("expected [job, reducers] at the start of the job");
+    };
+    let (job, reducers) = (text(job), parse_reducers(reducers)?);
+    std::fs::create_dir_all(output).with_context(|| format!("creating {}", output.display()))?;
+
+    let mut written = BTreeSet::new();
+    let mut keys = 0;
+    while written.len() < reducers {
+        let msg = pull_recv(&mut receiver).await?.into_vec();
+        let [partition, worker, pairs] = msg.as_slice() else {
+            eprintln!("ERROR: Malformed output");
+            continue;
+        };
+        let partition: usize = text(partition).parse()?;
+        if partition >= reducers {
+            eprintln!("ERROR: Output of partition {partition}, of {reducers}");
+            continue;
+        }
+        if written.contains(&partition) {
+            continue; // Already written
+        }
+        let count = match decode_pairs(pairs) {
+            Ok(pairs) => pairs.count(),
+            Err(e) => {
+                eprintln!("ERROR: Output of partition {partition}: {e:#}");
+                continue;
+            }
+        };
+        let path = output.join(format!("part-{partition:05}"));
+        std::fs::write(&path, pairs).with_context(|| format!("writing {}", path.display()))?;
+        written.insert(partition);
+        if control_addr.is_some() {
+            // Reduce tasks are batch 1 for the tracker of the ventilator
+            control.send(format!("{DONE} 1 {partition}").into()).await?;
+        }
+        keys += count;
+        println!(
+            "Wrote {} from worker {}",
+            path.display(),
+            String::from_utf8_lossy(worker)
+        );
+    }
+    println!(
+        "{job} done: {keys} keys in {reducers} files in {}",
+        output.display()
+    );
+
+    if control_addr.is_some() {
+        control.send(KILL.into()).await?; // Tell the workers to exit
+        tokio::time::sleep(Duration::from_millis(100)).await; // Give the message time to get out
+    }
+    receiver.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Input files in a fresh directory, and the directory of the output.
+    fn inputs(name: &str, files: &[(&str, &str)]) -> (Vec<PathBuf>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mapreduce-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = files
+            .iter()
+            .map(|(file, contents)| {
+                let path = dir.join(file);
+                std::fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect();
+        (paths, dir.join("output"))
+    }
+
+    /// Runs `job` with two workers on free ports, and returns the lines of
+    /// all the output files.
+    async fn run(job: &str, inputs: Vec<PathBuf>, output: &Path) -> BTreeMap<String, String> {
+        let port = || crate::run::Port::reserve().expect("a free port");
+        let (sender, shuffle, sink, register, control) = (port(), port(), port(), port(), port());
+        // Released by the roles that bind them, right before they start
+        let (sink, control) = (sink.release(), control.release());
+        let sink_task = tokio::spawn({
+            let output = output.to_path_buf();
+            async move { sink_handler(sink, &output, Some(control)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let plan = Plan {
+            job: job.to_string(),
+            inputs,
+            reducers: 3,
+            chunk_lines: 2,
+            min_workers: 2,
+            wait: None,
+            task_timeout: Duration::from_secs(2),
+        };
+        let (sender, shuffle, register) = (sender.release(), shuffle.release(), register.release());
+        let ventilator = tokio::spawn(ventilator_handler(
+            sender,
+            shuffle,
+            sink,
+            register,
+            Some(control),
+            plan,
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                tokio::spawn(worker_handler(
+                    sender,
+                    shuffle,
+                    sink,
+                    register,
+                    Some(control),
+                ))
+            })
+            .collect();
+
+        let all = async {
+            sink_task.await??;
+            ventilator.await??;
+            for worker in workers {
+                worker.await??;
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(10), all)
+            .await
+            .expect("the job finishes")
+            .unwrap();
+
+        let mut lines = BTreeMap::new();
+        for part in 0..3 {
+            let file = std::fs::read_to_string(output.join(format!("part-{part:05}"))).unwrap();
+            for (key, value) in decode_pairs(file.as_bytes()).unwrap() {
+                assert_eq!(partition(&key, 3), part);
+                assert!(lines.insert(key, value).is_none());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn separators_in_values_are_escaped() {
+        let pairs = vec![("word".to_string(), "a\tb\nc\\".to_string())];
+        let encoded = encode_pairs(pairs.iter().map(|(key, value)| (key, value)));
+        let decoded: Vec<_> = decode_pairs(&encoded).expect("UTF-8").collect();
+        assert_eq!(decoded, pairs);
+
+        let docs = vec!["a,b".to_string(), "c\\".to_string()];
+        assert_eq!(Index.reduce("word", docs), "a\\,b,c\\\\");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn word_count_of_local_files() {
+        let (inputs, output) = inputs(
+            "wordcount",
+            &[
+                ("a.txt", "the cat\nthe dog\nThe end\n"),
+                ("b.txt", "a dog, a cat\n\nand a bird\n"),
+            ],
+        );
+        let counts = run("wordcount", inputs, &output).await;
+        let expected = [
+            ("a", "3"),
+            ("and", "1"),
+            ("bird", "1"),
+            ("cat", "2"),
+            ("dog", "2"),
+            ("end", "1"),
+            ("the", "3"),
+        ];
+        let expected: BTreeMap<String, String> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(counts, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn inverted_index_of_local_files() {
+        let (inputs, output) = inputs(
+            "index",
+            &[
+                ("a.txt", "red fish\nblue fish\n"),
+                ("b.txt", "one fish\nred sky\n"),
+            ],
+        );
+        let docs = [
+            inputs[0].display().to_string(),
+            inputs[1].display().to_string(),
+        ];
+        let index = run("index", inputs, &output).await;
+        let both = format!("{},{}", docs[0], docs[1]);
+        assert_eq!(index["fish"], both);
+        assert_eq!(index["red"], both);
+        assert_eq!(index["blue"], docs[0]);
+        assert_eq!(index["one"], docs[1]);
+        assert_eq!(index["sky"], docs[1]);
+        assert_eq!(index.len(), 5);
+    }
+}
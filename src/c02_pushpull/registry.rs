@@ -0,0 +1,204 @@
+//! Worker registration for the `c02_pushpull` ventilator.
+//!
+//! Workers push `READY <id>` to the registration socket of the ventilator
+//! when they start and again after each task. The ventilator starts once
+//! enough workers registered, and then only sends a task for each `READY`
+//! it got, so tasks do not pile up at the first workers: a worker that joins
+//! late gets the next tasks as soon as it registers.
+//!
+//! Workers also push `TAKEN <id> <batch> <task>` when they get a task, so the
+//! ventilator knows which worker failed when a task times out.
+//!
+//! In credit mode (see `credit`), workers start with `CREDIT <id> <n>`
+//! instead of `READY <id>`, to take up to `n` tasks at once. The ventilator
+//! then counts the credits of each worker, and picks the one a task goes
+//! to. Without credit mode the PUSH socket picks it, so the `READY`s of all
+//! the workers are only counted together.
+
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use tokio::time::{Instant, timeout_at};
+use zeromq::prelude::*;
+
+use super::pull_recv;
+
+/// Message a worker sends when it can take a task.
+pub const READY: &str = "READY";
+/// Message a worker sends when it gets a task.
+pub const TAKEN: &str = "TAKEN";
+/// Message a worker sends when it can take several tasks.
+pub const CREDIT: &str = "CREDIT";
+
+/// A worker got a task.
+pub struct Taken {
+    pub worker: String,
+    pub batch: u64,
+    pub task: u64,
+}
+
+/// Registration socket of the ventilator.
+pub struct Registry {
+    sock: zeromq::PullSocket,
+    /// Whether tasks go to the worker with the most credits.
+    credit: bool,
+    /// Registered workers, with the tasks that can be sent to each of them
+    /// without piling up in credit mode.
+    credits: BTreeMap<String, usize>,
+    /// Tasks that can be sent to any worker without piling up, without
+    /// credit mode.
+    ready: usize,
+}
+
+impl Registry {
+    pub async fn bind(addr: SocketAddr, credit: bool) -> anyhow::Result<Self> {
+        let mut sock = zeromq::PullSocket::new();
+        sock.bind(format!("tcp://{addr}").as_str()).await?;
+        Ok(Self {
+            sock,
+            credit,
+            credits: BTreeMap::new(),
+            ready: 0,
+        })
+    }
+
+    /// Handles the next message of a worker, returning it if it is `TAKEN`.
+    pub async fn recv(&mut self) -> anyhow::Result<Option<Taken>> {
+        let msg =
+            String::try_from(pull_recv(&mut self.sock).await?).map_err(|e| anyhow::anyhow!(e))?;
+        let words: Vec<&str> = msg.split_whitespace().collect();
+        match words.as_slice() {
+            [READY, id] => self.grant(id, 1),
+            [CREDIT, id, credits] => match credits.parse() {
+                Ok(credits) => self.grant(id, credits),
+                Err(_) => eprintln!("ERROR: Unexpected registration message {msg:?}"),
+            },
+            [TAKEN, id, batch, task] => {
+                if let (Ok(batch), Ok(task)) = (batch.parse(), task.parse()) {
+                    let worker = id.to_string();
+                    return Ok(Some(Taken {
+                        worker,
+                        batch,
+                        task,
+                    }));
+                }
+            }
+            _ => eprintln!("ERROR: Unexpected registration message {msg:?}"),
+        }
+        Ok(None)
+    }
+
+    fn grant(&mut self, worker: &str, credits: usize) {
+        if !self.credits.contains_key(worker) {
+            let total = self.credits.len() + 1;
+            println!("Worker {worker} registered ({total} in total)");
+        }
+        let worker_credits = self.credits.entry(worker.to_string()).or_default();
+        match self.credit {
+            true => *worker_credits += credits,
+            false => self.ready += credits,
+        }
+    }
+
+    /// Waits until `min_workers` registered, or until `wait` elapsed if given.
+    /// Returns the number of registered workers.
+    pub async fn wait_for(
+        &mut self,
+        min_workers: usize,
+        wait: Option<Duration>,
+    ) -> anyhow::Result<usize> {
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        while self.credits.len() < min_workers {
+            match deadline {
+                Some(deadline) => match timeout_at(deadline, self.recv()).await {
+                    Ok(registered) => _ = registered?,
+                    Err(_) => break,
+                },
+                None => _ = self.recv().await?,
+            }
+        }
+        Ok(self.credits.len())
+    }
+
+    /// Gives back a `READY` used up for a task that could not be sent.
+    pub fn refund_ready(&mut self) {
+        self.ready += 1;
+    }
+
+    /// Forgets a worker that is gone, until it grants credit again.
+    pub fn forget(&mut self, worker: &str) {
+        if self.credits.remove(worker).is_some() {
+            println!("Worker {worker} is gone");
+        }
+    }
+
+    /// Uses up a `READY`, if a task can be sent now without credit mode.
+    pub fn take_ready(&mut self) -> bool {
+        match self.ready {
+            0 => false,
+            _ => {
+                self.ready -= 1;
+                true
+            }
+        }
+    }
+
+    /// Uses up a credit of the worker with the most, if any worker can take
+    /// a task now in credit mode, and returns that worker.
+    pub fn take_credit(&mut self) -> Option<String> {
+        let (worker, credits) = self
+            .credits
+            .iter_mut()
+            .filter(|(_, credits)| **credits > 0)
+            .max_by_key(|(_, credits)| **credits)?;
+        *credits -= 1;
+        Some(worker.clone())
+    }
+}
+
+/// A random id for a worker.
+pub fn worker_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+/// Registration socket of a worker.
+pub struct Registration {
+    sock: zeromq::PushSocket,
+    id: String,
+}
+
+impl Registration {
+    /// Registers with the ventilator as `id`, able to take `credits` tasks.
+    pub async fn connect(addr: SocketAddr, id: String, credits: usize) -> anyhow::Result<Self> {
+        let mut sock = zeromq::PushSocket::new();
+        sock.connect(format!("tcp://{addr}").as_str()).await?;
+        let mut registration = Self { sock, id };
+        match credits {
+            1 => registration.ready().await?,
+            _ => {
+                let credit = format!("{CREDIT} {} {credits}", registration.id);
+                registration.sock.send(credit.into()).await?;
+            }
+        }
+        Ok(registration)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Tells the ventilator this worker got a task.
+    pub async fn taken(&mut self, batch: u64, task: u64) -> anyhow::Result<()> {
+        self.sock
+            .send(format!("{TAKEN} {} {batch} {task}", self.id).into())
+            .await?;
+        Ok(())
+    }
+
+    /// Tells the ventilator this worker can take another task.
+    pub async fn ready(&mut self) -> anyhow::Result<()> {
+        self.sock
+            .send(format!("{READY} {}", self.id).into())
+            .await?;
+        Ok(())
+    }
+}
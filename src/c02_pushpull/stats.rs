@@ -0,0 +1,216 @@
+//! Per-worker statistics of the `c02_pushpull` sink.
+//!
+//! Each result carries the id of the worker that computed it and the
+//! milliseconds it spent on it. Once the last batch is done, the sink prints
+//! the tasks, mean and max task time and idle time of each worker, and the
+//! parallel efficiency of the run: the total expected cost of the batches
+//! (the "Total expected cost" of the ventilator) over the elapsed time of
+//! all the workers. With `sink --json <path>`, it also writes them as JSON.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+use super::tracker::TaskResult;
+
+/// What the sink knows about one worker.
+#[derive(Default)]
+struct Worker {
+    tasks: u64,
+    busy: u64,
+    max: u64,
+}
+
+impl Worker {
+    fn mean(&self) -> f64 {
+        self.busy as f64 / self.tasks.max(1) as f64
+    }
+
+    fn idle(&self, elapsed: u64) -> u64 {
+        elapsed.saturating_sub(self.busy)
+    }
+
+    /// Busy time over `elapsed`, in percent.
+    fn utilization(&self, elapsed: u64) -> f64 {
+        self.busy as f64 * 100.0 / elapsed.max(1) as f64
+    }
+}
+
+/// The statistics written by `sink --json`.
+#[derive(Serialize)]
+struct Report<'a> {
+    elapsed_msec: u64,
+    expected_cost_msec: Option<u64>,
+    parallel_efficiency: Option<f64>,
+    workers: Vec<WorkerReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct WorkerReport<'a> {
+    id: &'a str,
+    tasks: u64,
+    busy_msec: u64,
+    mean_msec: f64,
+    max_msec: u64,
+    idle_msec: u64,
+}
+
+/// Statistics of the workers over the whole run.
+pub struct Stats {
+    workers: BTreeMap<String, Worker>,
+    /// Sum of the expected costs of the finished batches, while all have one.
+    expected_cost: Option<u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            workers: BTreeMap::new(),
+            expected_cost: Some(0),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts a result, duplicates included since the worker did the work.
+    pub fn record(&mut self, result: &TaskResult) {
+        let worker = self.workers.entry(result.worker.clone()).or_default();
+        worker.tasks += 1;
+        worker.busy += result.busy;
+        worker.max = worker.max.max(result.busy);
+    }
+
+    /// Adds the expected cost of a finished batch; `None` if its kind has none.
+    pub fn finished(&mut self, expected_cost: Option<u64>) {
+        self.expected_cost = self.expected_cost.zip(expected_cost).map(|(a, b)| a + b);
+    }
+
+    /// Expected cost over the time all the workers had, in percent.
+    fn efficiency(&self, elapsed: u64) -> Option<f64> {
+        let capacity = elapsed.max(1) * self.workers.len().max(1) as u64;
+        self.expected_cost
+            .map(|cost| cost as f64 * 100.0 / capacity as f64)
+    }
+
+    /// Prints the table of the workers, `elapsed` msec after the first batch.
+    pub fn print(&self, elapsed: u64) {
+        println!(
+            "{:<10}{:>7}{:>12}{:>11}{:>11}{:>11}{:>13}",
+            "Worker", "Tasks", "Busy msec", "Mean msec", "Max msec", "Idle msec", "Utilization"
+        );
+        for (id, worker) in &self.workers {
+            let idle = worker.idle(elapsed);
+            let utilization = worker.utilization(elapsed);
+            println!(
+                "{id:<10}{:>7}{:>12}{:>11.1}{:>11}{idle:>11}{utilization:>12.0}%",
+                worker.tasks,
+                worker.busy,
+                worker.mean(),
+                worker.max
+            );
+        }
+        match self.efficiency(elapsed) {
+            Some(efficiency) => println!(
+                "Parallel efficiency: {efficiency:.0}% ({} msec expected on {} workers)",
+                self.expected_cost.unwrap_or_default(),
+                self.workers.len()
+            ),
+            None => println!("Parallel efficiency: n/a, no expected cost for all the batches"),
+        }
+    }
+
+    pub fn to_json(&self, elapsed: u64) -> String {
+        let report = Report {
+            elapsed_msec: elapsed,
+            expected_cost_msec: self.expected_cost,
+            parallel_efficiency: self.efficiency(elapsed).map(|e| e / 100.0),
+            workers: self
+                .workers
+                .iter()
+                .map(|(id, worker)| WorkerReport {
+                    id,
+                    tasks: worker.tasks,
+                    busy_msec: worker.busy,
+                    mean_msec: worker.mean(),
+                    max_msec: worker.max,
+                    idle_msec: worker.idle(elapsed),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&report).expect("the report serializes") + "\n"
+    }
+
+    pub fn write_json(&self, path: &Path, elapsed: u64) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_json(elapsed))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn result(worker: &str, busy: u64) -> TaskResult {
+        TaskResult {
+            batch: 0,
+            id: 0,
+            worker: worker.to_string(),
+            result: Bytes::new(),
+            busy,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn busy_idle_and_utilization_of_each_worker() {
+        let mut stats = Stats::default();
+        for busy in [100, 300, 200] {
+            stats.record(&result("a", busy));
+        }
+        let worker = &stats.workers["a"];
+        assert_eq!((worker.tasks, worker.busy, worker.max), (3, 600, 300));
+        assert_eq!(worker.mean(), 200.0);
+        assert_eq!(worker.idle(1000), 400);
+        assert_eq!(worker.utilization(1000), 60.0);
+        // Busier than the elapsed time, e.g. with tasks of a resumed run
+        assert_eq!(worker.idle(500), 0);
+    }
+
+    #[test]
+    fn zero_tasks_and_zero_elapsed_time() {
+        let worker = Worker::default();
+        assert_eq!(worker.mean(), 0.0);
+        assert_eq!(worker.idle(0), 0);
+        assert_eq!(worker.utilization(0), 0.0);
+
+        let mut stats = Stats::default();
+        stats.record(&result("a", 5));
+        assert_eq!(stats.workers["a"].utilization(0), 500.0);
+        assert_eq!(Stats::default().efficiency(0), Some(0.0));
+    }
+
+    #[test]
+    fn parallel_efficiency() {
+        let mut stats = Stats::default();
+        stats.record(&result("a", 400));
+        stats.record(&result("b", 400));
+        stats.finished(Some(600));
+        stats.finished(Some(400));
+        // 1000 msec of work in 1000 msec on 2 workers
+        assert_eq!(stats.efficiency(1000), Some(50.0));
+        assert_eq!(stats.efficiency(0), Some(50_000.0));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&stats.to_json(1000)).expect("valid JSON");
+        assert_eq!(json["parallel_efficiency"], 0.5);
+        assert_eq!(json["workers"][0]["idle_msec"], 600);
+
+        // A batch without an expected cost
+        stats.finished(None);
+        assert_eq!(stats.efficiency(1000), None);
+        stats.finished(Some(100));
+        assert_eq!(stats.efficiency(1000), None);
+    }
+}
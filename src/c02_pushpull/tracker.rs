@@ -0,0 +1,217 @@
+//! Task tracking for the `c02_pushpull` ventilator.
+//!
+//! Every task and result carries the ids of its batch and of its task.
+//! Workers tell the ventilator which task they took (see `registry`), and
+//! the sink publishes `DONE <batch> <id>` on its control socket for every
+//! result. Tasks without a result after the task timeout are sent again, and
+//! the worker that had taken them is reported as failed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use zeromq::ZmqMessage;
+
+use super::jobs::Kind;
+
+/// Published by the sink on the control socket for each result, followed
+/// by the batch and task ids.
+pub const DONE: &str = "DONE";
+
+fn number(frame: &[u8]) -> anyhow::Result<u64> {
+    Ok(String::from_utf8_lossy(frame).parse()?)
+}
+
+/// A task of a batch, with the payload of its kind.
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub batch: u64,
+    pub id: u64,
+    pub kind: Kind,
+    pub payload: Bytes,
+}
+
+impl Task {
+    /// `[batch, id, kind, payload]`
+    pub fn encode(&self) -> ZmqMessage {
+        let frames = vec![
+            Bytes::from(self.batch.to_string()),
+            Bytes::from(self.id.to_string()),
+            Bytes::from(self.kind.to_string()),
+            self.payload.clone(),
+        ];
+        ZmqMessage::try_from(frames).expect("four frames")
+    }
+
+    pub fn decode(msg: ZmqMessage) -> anyhow::Result<Self> {
+        let frames = msg.into_vec();
+        let [batch, id, kind, payload] = frames.as_slice() else {
+            anyhow::bail!(
+                "expected [batch, id, kind, payload], got {} frames",
+                frames.len()
+            );
+        };
+        Ok(Self {
+            batch: number(batch)?,
+            id: number(id)?,
+            kind: Kind::parse(&String::from_utf8_lossy(kind))?,
+            payload: payload.clone(),
+        })
+    }
+}
+
+/// The result of a task, from the worker that ran it.
+#[derive(Debug, Clone)]
+pub struct TaskResult {
+    pub batch: u64,
+    pub id: u64,
+    pub worker: String,
+    pub result: Bytes,
+    /// Milliseconds the worker spent on the task.
+    pub busy: u64,
+    /// Why the worker could not compute the result, if it could not.
+    pub error: Option<String>,
+}
+
+impl TaskResult {
+    /// `[batch, id, worker, result, busy, error]`, the error being empty on success.
+    pub fn encode(&self) -> ZmqMessage {
+        let frames = vec![
+            Bytes::from(self.batch.to_string()),
+            Bytes::from(self.id.to_string()),
+            Bytes::from(self.worker.clone()),
+            self.result.clone(),
+            Bytes::from(self.busy.to_string()),
+            Bytes::from(self.error.clone().unwrap_or_default()),
+        ];
+        ZmqMessage::try_from(frames).expect("six frames")
+    }
+
+    pub fn decode(frames: &[Bytes]) -> anyhow::Result<Self> {
+        let [batch, id, worker, result, busy, error] = frames else {
+            anyhow::bail!(
+                "expected [batch, id, worker, result, busy, error], got {} frames",
+                frames.len()
+            );
+        };
+        Ok(Self {
+            batch: number(batch)?,
+            id: number(id)?,
+            worker: String::from_utf8_lossy(worker).to_string(),
+            result: result.clone(),
+            busy: number(busy)?,
+            error: (!error.is_empty()).then(|| String::from_utf8_lossy(error).to_string()),
+        })
+    }
+}
+
+/// A task that can be sent again, with the batch and task ids it is
+/// tracked by.
+pub trait Tracked {
+    fn key(&self) -> (u64, u64);
+}
+
+impl Tracked for Task {
+    fn key(&self) -> (u64, u64) {
+        (self.batch, self.id)
+    }
+}
+
+struct Outstanding<T> {
+    task: T,
+    sent: Instant,
+    /// Worker that took the task, once it said so.
+    worker: Option<String>,
+}
+
+/// Tasks sent and not done yet.
+pub struct Tracker<T = Task> {
+    timeout: Duration,
+    /// By batch and task id.
+    outstanding: BTreeMap<(u64, u64), Outstanding<T>>,
+    /// Tasks sent again, with the worker that did not finish them.
+    retried: Vec<((u64, u64), Option<String>)>,
+}
+
+impl<T: Tracked> Tracker<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            outstanding: BTreeMap::new(),
+            retried: Vec::new(),
+        }
+    }
+
+    pub fn sent(&mut self, task: T, now: Instant) {
+        let key = task.key();
+        let outstanding = Outstanding {
+            task,
+            sent: now,
+            worker: None,
+        };
+        self.outstanding.insert(key, outstanding);
+    }
+
+    pub fn taken(&mut self, batch: u64, id: u64, worker: String) {
+        if let Some(outstanding) = self.outstanding.get_mut(&(batch, id)) {
+            outstanding.worker = Some(worker);
+        }
+    }
+
+    pub fn done(&mut self, batch: u64, id: u64) {
+        self.outstanding.remove(&(batch, id));
+    }
+
+    /// Removes the tasks that timed out, to be sent again.
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let expired: Vec<(u64, u64)> = self
+            .outstanding
+            .iter()
+            .filter(|(_, o)| now - o.sent >= self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| self.outstanding.remove(&id))
+            .map(|o| {
+                let (batch, id) = o.task.key();
+                match &o.worker {
+                    Some(worker) => println!("Task {batch}/{id} timed out at worker {worker}"),
+                    None => println!("Task {batch}/{id} timed out before a worker took it"),
+                }
+                self.retried.push(((batch, id), o.worker));
+                o.task
+            })
+            .collect()
+    }
+
+    pub fn report(&self) {
+        if self.retried.is_empty() {
+            println!("No task had to be retried");
+            return;
+        }
+        let retried: Vec<String> = self
+            .retried
+            .iter()
+            .map(|((batch, id), worker)| match worker {
+                Some(worker) => format!("{batch}/{id} (worker {worker})"),
+                None => format!("{batch}/{id} (lost before a worker took it)"),
+            })
+            .collect();
+        println!("Retried tasks: {}", retried.join(", "));
+        let failed: BTreeSet<&str> = self
+            .retried
+            .iter()
+            .filter_map(|(_, worker)| worker.as_deref())
+            .collect();
+        if !failed.is_empty() {
+            println!(
+                "Failed workers: {}",
+                failed.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+}
@@ -1,18 +1,99 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{future::pending, net::SocketAddr, path::PathBuf, time::Duration};
 
-use clap::Parser;
+use bytes::Bytes;
+use clap::{CommandFactory, Parser};
 use rand::Rng;
-use tokio::{io::AsyncWriteExt, time::sleep};
-use zeromq::prelude::*;
+use tokio::{
+    io::AsyncWriteExt,
+    time::{Instant, interval, sleep},
+};
+use zeromq::{ZmqMessage, prelude::*};
+
+use crate::{
+    admin::{self, Admin, Monitors, Stats},
+    capture::{self, Direction, Tap},
+};
+
+mod acl;
+mod lvc;
+mod middleware;
+mod subscriptions;
+mod tree;
+
+use acl::{Acl, Credentials, Gate};
+use lvc::Cache;
+use middleware::Chain;
+use subscriptions::{Announcer, Subscriptions};
+use tree::Hop;
 
 #[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)] // parsed once, at startup
 enum Mode {
     /// Run the Publisher, specifying the publish addr.
-    Publisher { addr: SocketAddr },
+    Publisher {
+        addr: SocketAddr,
+        #[command(flatten)]
+        credentials: Credentials,
+    },
     /// Run the Subscriber, specifying the remote addr and topic.
-    Subscriber { addr: SocketAddr, topic: u32 },
+    Subscriber {
+        addr: SocketAddr,
+        topic: u32,
+        /// Also announce the subscription to the broker's subscriptions addr.
+        #[arg(long)]
+        subs: Option<SocketAddr>,
+        /// Print the brokers each update went through, and the time each hop took.
+        #[arg(long)]
+        hops: bool,
+        #[command(flatten)]
+        credentials: Credentials,
+    },
     /// Run the broker, specifying the binds of the subscriber and the publish.
-    Broker { sub_addr: SocketAddr, pub_addr: SocketAddr },
+    Broker {
+        sub_addr: SocketAddr,
+        pub_addr: SocketAddr,
+        /// Republish every forwarded frame on a PUB socket bound here.
+        #[arg(long)]
+        capture: Option<SocketAddr>,
+        /// Answer admin commands (stats, peers, queues, reset) on a REP socket bound here.
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+        /// Receive subscriptions here and forward them to the publishers,
+        /// instead of subscribing to everything.
+        #[arg(long)]
+        subs: Option<SocketAddr>,
+        /// Maximum number of topics kept in the last value cache, which is only
+        /// replayed to the subscriptions received with `--subs`.
+        #[arg(long, default_value_t = 1000)]
+        cache_size: usize,
+        /// Seconds after which a cached value is too old to be sent.
+        #[arg(long, default_value_t = 60)]
+        cache_ttl: u64,
+        /// Do not send the last value of a topic to its new subscribers.
+        #[arg(long)]
+        no_cache: bool,
+        /// Name of the broker in the traces of the updates (defaults to its sub_addr).
+        #[arg(long)]
+        name: Option<String>,
+        /// Also subscribe to the frontend of this parent broker.
+        #[arg(long)]
+        upstream: Option<SocketAddr>,
+        /// Announce the subscriptions of this broker to the subscriptions addr of the parent.
+        #[arg(long, requires = "upstream")]
+        upstream_subs: Option<SocketAddr>,
+        /// Only accept the updates and subscriptions allowed by this policy file;
+        /// subscribers then get their updates on the subscriptions socket.
+        #[arg(long, requires = "subs", conflicts_with = "upstream")]
+        policy: Option<PathBuf>,
+        /// Append the denials to this file instead of printing them.
+        #[arg(long, requires = "policy")]
+        audit: Option<PathBuf>,
+        /// Run the updates through the stages listed in this file, reloaded when it changes.
+        #[arg(long)]
+        middleware: Option<PathBuf>,
+    },
+    /// Inspect the traffic captured by a broker, specifying its capture addr.
+    Inspect { addr: SocketAddr },
 }
 
 #[derive(clap::Parser)]
@@ -21,6 +102,11 @@ struct Cli {
     cmd: Mode,
 }
 
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
 pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -29,32 +115,222 @@ pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<(
 }
 
 pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
-    let cli = Cli::parse_from(args.into_iter());
+    let cli = Cli::parse_from(args);
 
     match cli.cmd {
-        Mode::Publisher { addr } => pub_handler(addr).await,
-        Mode::Subscriber { addr, topic } => sub_handler(addr, topic).await,
-        Mode::Broker { sub_addr, pub_addr } => broker_handler(sub_addr, pub_addr).await,
+        Mode::Publisher { addr, credentials } => pub_handler(addr, credentials).await,
+        Mode::Subscriber {
+            addr,
+            topic,
+            subs,
+            hops,
+            credentials,
+        } => sub_handler(addr, topic, subs, hops, credentials).await,
+        Mode::Broker {
+            sub_addr,
+            pub_addr,
+            capture,
+            admin,
+            subs,
+            cache_size,
+            cache_ttl,
+            no_cache,
+            name,
+            upstream,
+            upstream_subs,
+            policy,
+            audit,
+            middleware,
+        } => {
+            let stages = Stages {
+                cache: match no_cache || subs.is_none() {
+                    true => None,
+                    false => Some(Cache::new(cache_size, Duration::from_secs(cache_ttl))),
+                },
+                acl: match policy {
+                    Some(policy) => Some(Acl::load(&policy, audit.as_deref())?),
+                    None => None,
+                },
+                chain: match middleware {
+                    Some(path) => Some(Chain::load(path)?),
+                    None => None,
+                },
+            };
+            let hop = Hop {
+                name: name.unwrap_or(sub_addr.to_string()),
+                parent: upstream,
+                parent_subs: upstream_subs,
+            };
+            broker_handler(sub_addr, pub_addr, capture, admin, subs, stages, hop).await
+        }
+        Mode::Inspect { addr } => capture::inspect_handler(addr).await,
     }
 }
 
-async fn broker_handler(sub_addr: SocketAddr, pub_addr: SocketAddr) -> anyhow::Result<()> {
+/// Optional stages of the broker loop.
+struct Stages {
+    cache: Option<Cache>,
+    acl: Option<Acl>,
+    chain: Option<Chain>,
+}
+
+/// Sends `msg` to one subscriber through the subscriptions socket.
+fn addressed(subscriber: &Bytes, msg: &ZmqMessage) -> ZmqMessage {
+    let mut msg = msg.clone();
+    msg.push_front(subscriber.clone());
+    msg
+}
+
+async fn broker_handler(
+    sub_addr: SocketAddr,
+    pub_addr: SocketAddr,
+    capture_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    subs_addr: Option<SocketAddr>,
+    stages: Stages,
+    hop: Hop,
+) -> anyhow::Result<()> {
+    let mut monitors = Monitors::new(admin_addr);
     let mut frontend = zeromq::PubSocket::new();
+    monitors.watch("subscriber", &mut frontend);
     frontend.bind(format!("tcp://{sub_addr}").as_str()).await?;
     let mut backend = zeromq::SubSocket::new();
+    monitors.watch("publisher", &mut backend);
     backend.bind(format!("tcp://{pub_addr}").as_str()).await?;
-    backend.subscribe("").await?;
-    
+    if let Some(parent) = hop.parent {
+        backend.connect(format!("tcp://{parent}").as_str()).await?;
+    }
+    let mut parent = match hop.parent_subs {
+        Some(addr) => Some(Announcer::connect(addr).await?),
+        None => None,
+    };
+    let mut subs_sock = match subs_addr {
+        Some(addr) => {
+            let mut sock = zeromq::RouterSocket::new();
+            sock.bind(format!("tcp://{addr}").as_str()).await?;
+            Some(sock)
+        }
+        None => {
+            backend.subscribe("").await?;
+            if let Some(parent) = &mut parent {
+                parent.subscribe("").await?;
+            }
+            None
+        }
+    };
+    let mut subscriptions = Subscriptions::default();
+    let mut expiry = interval(subscriptions::KEEPALIVE);
+    let mut tap = Tap::bind(capture_addr).await?;
+    let mut admin = Admin::bind(admin_addr).await?;
+    let mut stats = Stats::default();
+    let Stages {
+        mut cache,
+        mut acl,
+        mut chain,
+    } = stages;
+    let mut reload_check = interval(middleware::RELOAD_CHECK);
+
     loop {
-        let message = backend.recv().await?;
-        frontend.send(message).await?;
+        tokio::select! {
+            message = backend.recv() => {
+                let mut message = message?;
+                if let Some(acl) = &mut acl {
+                    let Some(allowed) = acl.publication(message) else {
+                        continue;
+                    };
+                    message = allowed;
+                }
+                let messages = match &mut chain {
+                    Some(chain) => chain.run(message, Instant::now()),
+                    None => vec![message],
+                };
+                for message in messages {
+                    let Some(message) = tree::stamp(message, &hop.name) else {
+                        eprintln!("Dropped an update that already went through {}", hop.name);
+                        continue;
+                    };
+                    capture::capture(&mut tap, Direction::BackendToFrontend, &message).await;
+                    stats.record(Direction::BackendToFrontend, &message);
+                    if let Some(cache) = &mut cache {
+                        cache.store(&tree::unstamped(&message), Instant::now());
+                    }
+                    match (&acl, &mut subs_sock) {
+                        (Some(_), Some(sock)) => {
+                            let update = message.get(0).cloned().unwrap_or_default();
+                            // Fails for a subscriber that left, until its
+                            // subscriptions expire: that must not stop the broker.
+                            for subscriber in subscriptions.subscribers_of(&update) {
+                                _ = sock.send(addressed(&subscriber, &message)).await;
+                            }
+                        }
+                        _ => frontend.send(message).await?,
+                    }
+                }
+            }
+            msg = async {
+                match &mut subs_sock {
+                    Some(sock) => sock.recv().await,
+                    None => pending().await,
+                }
+            } => {
+                let msg = msg?;
+                let subscriber = msg.get(0).cloned().unwrap_or_default();
+                let gate = acl.as_mut().map_or(Gate::Pass, |acl| acl.subscription(&msg));
+                if let Gate::Stop(reply) = gate {
+                    if let (Some(reply), Some(sock)) = (reply, &mut subs_sock) {
+                        sock.send(reply).await?;
+                    }
+                    continue;
+                }
+                let handled = subscriptions.handle(msg, Instant::now());
+                if let Some(change) = handled.upstream {
+                    subscriptions::forward(&mut backend, &mut parent, change).await?;
+                }
+                if let (Some(cache), Some(topic)) = (&mut cache, handled.subscribed) {
+                    for message in cache.matching(&topic, Instant::now()) {
+                        let Some(message) = tree::stamp(message, &hop.name) else {
+                            continue;
+                        };
+                        match (&acl, &mut subs_sock) {
+                            (Some(_), Some(sock)) => sock.send(addressed(&subscriber, &message)).await?,
+                            _ => frontend.send(message).await?,
+                        }
+                    }
+                }
+            }
+            _ = expiry.tick() => {
+                for change in subscriptions.expire(Instant::now()) {
+                    subscriptions::forward(&mut backend, &mut parent, change).await?;
+                }
+                if let Some(parent) = &mut parent {
+                    parent.keepalive().await?;
+                }
+            }
+            _ = reload_check.tick() => {
+                if let Some(chain) = &mut chain {
+                    chain.reload_if_changed();
+                }
+            }
+            (role, event) = monitors.next() => stats.event(role, event),
+            command = admin::recv(&mut admin) => {
+                // Messages are never queued: they go out as soon as they arrive.
+                stats.queue("updates", 0);
+                stats.queue("subscriptions", subscriptions.refcounts().map(|(_, n)| n).sum());
+                stats.queue("cache", cache.as_ref().map_or(0, Cache::len));
+                admin::answer(&mut admin, &mut stats, &command?).await?;
+            }
+        }
     }
 }
 
-async fn pub_handler(bind_addr: SocketAddr) -> anyhow::Result<()> {
+async fn pub_handler(bind_addr: SocketAddr, credentials: Credentials) -> anyhow::Result<()> {
     let mut sock = zeromq::PubSocket::new();
     sock.connect(format!("tcp://{bind_addr}").as_str()).await?;
 
+    let mut signer = match (&credentials.identity, &credentials.secret) {
+        (Some(name), Some(secret)) => Some(acl::Signer::new(name, secret)),
+        _ => None,
+    };
     let mut rng = rand::rng();
     loop {
         let zipcode = rng.random_range(0..10);
@@ -63,7 +339,12 @@ async fn pub_handler(bind_addr: SocketAddr) -> anyhow::Result<()> {
         let update = format!(
             "Update for {zipcode:05}:\n  Temperature: {temperature}ºC\n  Humidity: {relhumidity}%.\n"
         );
-        let Err(e) = sock.send(update.into()).await else {
+        let signature = signer.as_mut().map(|signer| signer.sign(update.as_bytes()));
+        let mut message = ZmqMessage::from(update);
+        if let Some(signature) = signature {
+            message.push_back(signature);
+        }
+        let Err(e) = sock.send(message).await else {
             sleep(Duration::from_millis(1)).await;
             continue;
         };
@@ -72,20 +353,74 @@ async fn pub_handler(bind_addr: SocketAddr) -> anyhow::Result<()> {
     }
 }
 
-async fn sub_handler(connect_addr: SocketAddr, topic: u32) -> anyhow::Result<()> {
+async fn sub_handler(
+    connect_addr: SocketAddr,
+    topic: u32,
+    subs_addr: Option<SocketAddr>,
+    hops: bool,
+    credentials: Credentials,
+) -> anyhow::Result<()> {
     println!("Connecting to weather server...");
+    let topic = format!("Update for {topic:05}:\n");
     let mut sock = zeromq::SubSocket::new();
-    sock.subscribe(format!("Update for {topic:05}:\n").as_str())
+    sock.subscribe(topic.as_str())
         .await?;
     sock.connect(format!("tcp://{connect_addr}").as_str())
         .await?;
+    let mut announcer = match subs_addr {
+        Some(addr) => {
+            let mut announcer = Announcer::connect(addr).await?;
+            if let (Some(name), Some(secret)) = (&credentials.identity, &credentials.secret) {
+                announcer.handshake(name, secret).await?;
+            }
+            announcer.subscribe(&topic).await?;
+            Some(announcer)
+        }
+        None => None,
+    };
+    let mut keepalive = interval(subscriptions::KEEPALIVE);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
 
     loop {
-        match sock.recv().await {
+        let msg = tokio::select! {
+            msg = sock.recv() => msg,
+            // Brokers with a policy send the updates on the subscriptions socket.
+            msg = async {
+                match &mut announcer {
+                    Some(announcer) => announcer.recv().await,
+                    None => pending().await,
+                }
+            } => {
+                let msg = msg?;
+                if let Some((&subscriptions::DENIED, topic)) = msg.get(0).and_then(|f| f.split_first()) {
+                    eprintln!("Subscription to {:?} denied", String::from_utf8_lossy(topic));
+                    continue;
+                }
+                Ok(msg)
+            }
+            _ = keepalive.tick() => {
+                if let Some(announcer) = &mut announcer {
+                    announcer.keepalive().await?;
+                }
+                continue;
+            }
+            _ = &mut ctrl_c => {
+                if let Some(announcer) = announcer {
+                    announcer.close().await?;
+                }
+                return Ok(());
+            }
+        };
+        match msg {
             Ok(msg) => {
-                let msg = msg.into_vec();
-                for b in msg {
-                    tokio::io::stdout().write_all(&b).await?;
+                // The first frame is the update, the trace of the brokers comes after.
+                if let Some(update) = msg.get(0) {
+                    tokio::io::stdout().write_all(update).await?;
+                }
+                if hops {
+                    let hops = format!("  Via: {}\n", tree::describe(&msg));
+                    tokio::io::stdout().write_all(hops.as_bytes()).await?;
                 }
                 tokio::io::stdout().flush().await?;
             }
@@ -0,0 +1,497 @@
+//! Topic-level access control for the `c02_xpubxsub` broker.
+//!
+//! A broker started with `--policy <file>` only accepts updates and
+//! subscriptions allowed by the policy. The file has one section per client,
+//! with its shared secret and the zip code prefixes it may publish and
+//! subscribe to (`*` allows everything):
+//!
+//! ```text
+//! [porto]
+//! secret = s3cret
+//! publish = 040, 041
+//! subscribe = *
+//! ```
+//!
+//! The `zeromq` crate has no CURVE support, so clients prove who they are
+//! with an HMAC-SHA256 of their secret:
+//! - subscribers send a `\x03name:timestamp:signature` handshake to the
+//!   subscriptions socket before subscribing, and then get their updates on
+//!   it, since anyone could read the PUB socket of the broker;
+//! - PUB/SUB does not tell the broker who sent an update, so publishers add
+//!   a `name:nonce:signature` frame to every update instead, the nonce being
+//!   the time in microseconds, bumped so that it always increases.
+//!
+//! Handshakes and updates that are too old, or whose timestamp or nonce is
+//! not newer than the last one of their client, are refused as replays.
+//!
+//! Denials are answered with `\x02topic` on the subscriptions socket and
+//! written to the audit log.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context, anyhow, bail};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeromq::ZmqMessage;
+
+use super::subscriptions::{DENIED, HANDSHAKE, SUBSCRIBE};
+use crate::clock::now_ms;
+
+/// Identity of a publisher or subscriber, for brokers with a policy.
+#[derive(Debug, clap::Args)]
+pub struct Credentials {
+    /// Name of this client in the policy of the broker.
+    #[arg(long, requires = "secret")]
+    pub identity: Option<String>,
+    /// Shared secret of this client.
+    #[arg(long, requires = "identity")]
+    pub secret: Option<String>,
+}
+
+/// Handshakes and updates older than this are refused.
+const MAX_AGE_MS: u64 = 30_000;
+
+/// Prefix of all the topics, followed by the zip code.
+const TOPIC_PREFIX: &str = "Update for ";
+
+/// What a client may do.
+#[derive(Debug, Default)]
+struct Rights {
+    secret: String,
+    publish: Vec<String>,
+    subscribe: Vec<String>,
+}
+
+/// Whether `topic` (or the update it starts) is under one of the zip code prefixes.
+fn allowed(prefixes: &[String], topic: &[u8]) -> bool {
+    let Some(zipcode) = topic.strip_prefix(TOPIC_PREFIX.as_bytes()) else {
+        return prefixes.iter().any(|p| p == "*");
+    };
+    prefixes
+        .iter()
+        .any(|p| p == "*" || zipcode.starts_with(p.as_bytes()))
+}
+
+fn parse_policy(text: &str) -> anyhow::Result<HashMap<String, Rights>> {
+    let mut clients = HashMap::new();
+    let mut current: Option<String> = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            clients.insert(name.to_string(), Rights::default());
+            current = Some(name.to_string());
+            continue;
+        }
+        let (Some(name), Some((key, value))) = (&current, line.split_once('=')) else {
+            bail!("line {}: expected `[name]` or `key = value`", n + 1);
+        };
+        let rights = clients.get_mut(name).expect("inserted with its section");
+        let list = || value.split(',').map(|p| p.trim().to_string()).collect();
+        match key.trim() {
+            "secret" => rights.secret = value.trim().to_string(),
+            "publish" => rights.publish = list(),
+            "subscribe" => rights.subscribe = list(),
+            other => bail!("line {}: unknown key `{other}`", n + 1),
+        }
+    }
+    Ok(clients)
+}
+
+fn hmac(secret: &str, data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key size");
+    mac.update(data);
+    mac
+}
+
+fn sign(secret: &str, data: &[u8]) -> String {
+    hmac(secret, data)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Whether `signature` (in hex) is the one of `data`, compared in constant time.
+fn verify_signature(secret: &str, data: &[u8], signature: &str) -> bool {
+    if !signature.len().is_multiple_of(2) || !signature.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return false;
+    }
+    let bytes: Vec<u8> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).expect("hex digits"))
+        .collect();
+    hmac(secret, data).verify_slice(&bytes).is_ok()
+}
+
+/// What the signature of an update covers: its nonce, then the update.
+fn signed_update(nonce: u64, update: &[u8]) -> Vec<u8> {
+    [format!("{nonce}:").as_bytes(), update].concat()
+}
+
+/// Signs the updates of a publisher.
+pub struct Signer {
+    name: String,
+    secret: String,
+    last_nonce: u64,
+}
+
+impl Signer {
+    pub fn new(name: &str, secret: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            secret: secret.to_string(),
+            last_nonce: 0,
+        }
+    }
+
+    /// Signature frame to add to `update`.
+    pub fn sign(&mut self, update: &[u8]) -> Bytes {
+        let nonce = (now_ms() * 1000).max(self.last_nonce + 1);
+        self.last_nonce = nonce;
+        let signature = sign(&self.secret, &signed_update(nonce, update));
+        Bytes::from(format!("{}:{nonce}:{signature}", self.name))
+    }
+}
+
+/// Handshake frame a subscriber sends before subscribing.
+pub fn handshake(name: &str, secret: &str) -> Bytes {
+    let proof = format!("{name}:{}", now_ms());
+    let mut frame = vec![HANDSHAKE];
+    frame.extend_from_slice(format!("{proof}:{}", sign(secret, proof.as_bytes())).as_bytes());
+    Bytes::from(frame)
+}
+
+/// Outcome of checking a message of the subscriptions socket.
+pub enum Gate {
+    /// Let the subscriptions see it.
+    Pass,
+    /// Stop it there, sending this reply if any.
+    Stop(Option<ZmqMessage>),
+}
+
+/// Refuses a timestamp or nonce `stamp`, taken at `stamp_ms`, if it is too
+/// old (or too far ahead) or not newer than the `last` one of its client;
+/// records it otherwise.
+fn fresh(last: &mut u64, stamp: u64, stamp_ms: u64) -> Result<(), &'static str> {
+    if now_ms().abs_diff(stamp_ms) > MAX_AGE_MS {
+        return Err("stale");
+    }
+    if stamp <= *last {
+        return Err("replayed");
+    }
+    *last = stamp;
+    Ok(())
+}
+
+/// The policy of the broker and the subscribers that proved their identity.
+pub struct Acl {
+    clients: HashMap<String, Rights>,
+    /// Names of the authenticated subscribers, by socket identity.
+    sessions: HashMap<Bytes, String>,
+    /// Last handshake timestamp and update nonce of each client.
+    last_handshake: HashMap<String, u64>,
+    last_nonce: HashMap<String, u64>,
+    audit: Option<File>,
+}
+
+impl Acl {
+    pub fn load(policy: &Path, audit: Option<&Path>) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(policy)
+            .with_context(|| format!("reading {}", policy.display()))?;
+        let clients = parse_policy(&text).with_context(|| format!("in {}", policy.display()))?;
+        let audit = match audit {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        println!("Loaded the policy of {} clients", clients.len());
+        Ok(Self::new(clients, audit))
+    }
+
+    fn new(clients: HashMap<String, Rights>, audit: Option<File>) -> Self {
+        Self {
+            clients,
+            sessions: HashMap::new(),
+            last_handshake: HashMap::new(),
+            last_nonce: HashMap::new(),
+            audit,
+        }
+    }
+
+    fn deny(&mut self, action: &str, who: &str, topic: &[u8], reason: &str) {
+        let line = format!(
+            "{} DENY {action} by {who} of {:?}: {reason}\n",
+            now_ms(),
+            String::from_utf8_lossy(topic)
+        );
+        match &mut self.audit {
+            Some(file) => {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    eprintln!("Error writing the audit log: {e}");
+                }
+            }
+            None => eprint!("{line}"),
+        }
+    }
+
+    /// Verifies the signature frame of an update from a publisher and strips
+    /// it, or returns `None` if the update must be dropped.
+    pub fn publication(&mut self, msg: ZmqMessage) -> Option<ZmqMessage> {
+        let mut frames = msg.into_vec();
+        let signature = match frames.len() {
+            2 => frames.pop(),
+            _ => None,
+        };
+        let update = frames.first()?;
+        let Err((who, reason)) = self.check_publication(update, signature) else {
+            return ZmqMessage::try_from(frames).ok();
+        };
+        let topic = update.split(|&b| b == b'\n').next().unwrap_or_default();
+        self.deny("publish", &who, topic, reason);
+        None
+    }
+
+    /// Returns who sent the update and why it is refused, if it is.
+    fn check_publication(
+        &mut self,
+        update: &[u8],
+        signature: Option<Bytes>,
+    ) -> Result<(), (String, &'static str)> {
+        let signature = signature.ok_or(("?".to_string(), "unsigned"))?;
+        let signature = String::from_utf8_lossy(&signature).to_string();
+        let mut parts = signature.splitn(3, ':');
+        let (Some(name), Some(nonce), Some(mac)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(("?".to_string(), "malformed signature"));
+        };
+        let refuse = |reason| Err((name.to_string(), reason));
+        let Some(rights) = self.clients.get(name) else {
+            return refuse("unknown client");
+        };
+        let Ok(nonce) = nonce.parse::<u64>() else {
+            return refuse("malformed signature");
+        };
+        if !verify_signature(&rights.secret, &signed_update(nonce, update), mac) {
+            return refuse("bad signature");
+        }
+        if !allowed(&rights.publish, update) {
+            return refuse("topic not allowed");
+        }
+        let last = self.last_nonce.entry(name.to_string()).or_default();
+        if let Err(reason) = fresh(last, nonce, nonce / 1000) {
+            return refuse(reason);
+        }
+        Ok(())
+    }
+
+    /// Checks a `[subscriber, frame]` message of the subscriptions socket:
+    /// handshakes are consumed here, and subscriptions are only let through
+    /// if the subscriber may read the topic.
+    pub fn subscription(&mut self, msg: &ZmqMessage) -> Gate {
+        let (Some(subscriber), Some(frame)) = (msg.get(0), msg.get(1)) else {
+            return Gate::Pass;
+        };
+        match frame.split_first() {
+            Some((&HANDSHAKE, proof)) => {
+                let proof = String::from_utf8_lossy(proof).to_string();
+                match self.verify(&proof) {
+                    Ok(name) => {
+                        println!("Subscriber {name} authenticated");
+                        self.sessions.insert(subscriber.clone(), name);
+                    }
+                    Err(e) => {
+                        let name = proof.split(':').next().unwrap_or("?").to_string();
+                        self.deny("handshake", &name, b"", &e.to_string());
+                    }
+                }
+                Gate::Stop(None)
+            }
+            Some((&SUBSCRIBE, topic)) => {
+                let name = self.sessions.get(subscriber).cloned();
+                let reason = match &name {
+                    None => "not authenticated",
+                    Some(name) if !allowed(&self.clients[name].subscribe, topic) => {
+                        "topic not allowed"
+                    }
+                    Some(_) => return Gate::Pass,
+                };
+                self.deny("subscribe", name.as_deref().unwrap_or("?"), topic, reason);
+                let mut denied = vec![DENIED];
+                denied.extend_from_slice(topic);
+                let reply = vec![subscriber.clone(), Bytes::from(denied)];
+                Gate::Stop(ZmqMessage::try_from(reply).ok())
+            }
+            _ => Gate::Pass,
+        }
+    }
+
+    /// Checks a `name:timestamp:signature` handshake and returns the name.
+    fn verify(&mut self, proof: &str) -> anyhow::Result<String> {
+        let mut parts = proof.rsplitn(2, ':');
+        let (Some(mac), Some(signed)) = (parts.next(), parts.next()) else {
+            bail!("malformed handshake");
+        };
+        let (name, timestamp) = signed
+            .split_once(':')
+            .ok_or(anyhow!("malformed handshake"))?;
+        let rights = self.clients.get(name).ok_or(anyhow!("unknown client"))?;
+        if !verify_signature(&rights.secret, signed.as_bytes(), mac) {
+            bail!("bad signature");
+        }
+        let timestamp: u64 = timestamp.parse()?;
+        let last = self.last_handshake.entry(name.to_string()).or_default();
+        if let Err(reason) = fresh(last, timestamp, timestamp) {
+            bail!("{reason} handshake");
+        }
+        Ok(name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = "
+        # Porto publishes its own zip codes
+        [porto]
+        secret = s3cret
+        publish = 040, 041
+        subscribe = *
+
+        [reader]
+        secret = other
+        subscribe = 040
+    ";
+
+    fn acl() -> Acl {
+        Acl::new(parse_policy(POLICY).expect("valid policy"), None)
+    }
+
+    fn update(zipcode: &str) -> Vec<u8> {
+        format!("{TOPIC_PREFIX}{zipcode}:\n  Temperature: 20ºC\n").into_bytes()
+    }
+
+    fn signed_message(signer: &mut Signer, update: &[u8]) -> ZmqMessage {
+        let mut msg = ZmqMessage::from(update.to_vec());
+        msg.push_back(signer.sign(update));
+        msg
+    }
+
+    #[test]
+    fn parses_a_policy() {
+        let clients = parse_policy(POLICY).expect("valid policy");
+        assert_eq!(clients.len(), 2);
+        let porto = &clients["porto"];
+        assert_eq!(porto.secret, "s3cret");
+        assert_eq!(porto.publish, ["040", "041"]);
+        assert_eq!(porto.subscribe, ["*"]);
+        assert!(clients["reader"].publish.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_policies() {
+        assert!(parse_policy("secret = s3cret").is_err());
+        assert!(parse_policy("[porto]\nsecret").is_err());
+        assert!(parse_policy("[porto]\nadmin = yes").is_err());
+    }
+
+    #[test]
+    fn allows_topics_under_a_prefix() {
+        let prefixes = ["040".to_string(), "1".to_string()];
+        assert!(allowed(&prefixes, &update("04012")));
+        assert!(allowed(&prefixes, &update("10000")));
+        assert!(!allowed(&prefixes, &update("04100")));
+        assert!(!allowed(&prefixes, b"Something else"));
+        assert!(allowed(&["*".to_string()], b"Something else"));
+        assert!(!allowed(&[], &update("04012")));
+    }
+
+    #[test]
+    fn verifies_handshakes() {
+        let mut acl = acl();
+        let proof = handshake("porto", "s3cret");
+        let proof = String::from_utf8_lossy(&proof[1..]).to_string();
+        assert_eq!(acl.verify(&proof).expect("valid handshake"), "porto");
+        assert!(acl.verify(&proof).is_err(), "replayed handshake");
+
+        let proof = handshake("porto", "wrong");
+        assert!(acl.verify(&String::from_utf8_lossy(&proof[1..])).is_err());
+        let proof = handshake("nobody", "s3cret");
+        assert!(acl.verify(&String::from_utf8_lossy(&proof[1..])).is_err());
+        assert!(acl.verify("porto").is_err());
+        assert!(acl.verify("porto:notatime:00").is_err());
+    }
+
+    #[test]
+    fn refuses_stale_handshakes() {
+        let mut acl = acl();
+        let signed = format!("porto:{}", now_ms() - MAX_AGE_MS - 1);
+        let proof = format!("{signed}:{}", sign("s3cret", signed.as_bytes()));
+        assert!(acl.verify(&proof).is_err());
+    }
+
+    #[test]
+    fn checks_signed_publications() {
+        let mut acl = acl();
+        let mut signer = Signer::new("porto", "s3cret");
+        let update = update("04012");
+
+        let msg = signed_message(&mut signer, &update);
+        let accepted = acl.publication(msg.clone()).expect("signed update");
+        assert_eq!(accepted.into_vec(), [Bytes::from(update.clone())]);
+        assert!(acl.publication(msg).is_none(), "replayed update");
+        assert!(
+            acl.publication(signed_message(&mut signer, &update))
+                .is_some()
+        );
+
+        assert!(acl.publication(ZmqMessage::from(update.clone())).is_none());
+        let mut tampered = ZmqMessage::from(self::update("04013"));
+        tampered.push_back(signer.sign(&update));
+        assert!(acl.publication(tampered).is_none());
+        let not_allowed = self::update("05000");
+        assert!(
+            acl.publication(signed_message(&mut signer, &not_allowed))
+                .is_none()
+        );
+        let mut reader = Signer::new("reader", "other");
+        assert!(
+            acl.publication(signed_message(&mut reader, &update))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn refuses_stale_publications() {
+        let mut acl = acl();
+        let update = update("04012");
+        let nonce = (now_ms() - MAX_AGE_MS - 1) * 1000;
+        let signature = sign("s3cret", &signed_update(nonce, &update));
+        let mut msg = ZmqMessage::from(update);
+        msg.push_back(Bytes::from(format!("porto:{nonce}:{signature}")));
+        assert!(acl.publication(msg).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let update = update("04012");
+        let mac = sign("s3cret", &update);
+        assert!(!verify_signature("s3cret", &update, &mac[1..]));
+        assert!(!verify_signature(
+            "s3cret",
+            &update,
+            &format!("{}zz", &mac[2..])
+        ));
+        assert!(!verify_signature("s3cret", &update, ""));
+        assert!(verify_signature("s3cret", &update, &mac));
+    }
+}
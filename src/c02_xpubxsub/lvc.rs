@@ -0,0 +1,81 @@
+//! Last Value Cache for the `c02_xpubxsub` broker.
+//!
+//! The broker keeps the most recent update of each topic (the first line of
+//! the update, e.g. `Update for 00003:\n`). When a subscriber announces a new
+//! subscription (see `subscriptions`), the cached updates matching it are
+//! published again, so the subscriber does not have to wait for the next one.
+//! As with a real LVC proxy, other subscribers of the same topic get that
+//! update twice.
+//!
+//! Updates are cached without their trace (see `tree`) and stamped again when
+//! they are replayed, so `--hops` does not count the time spent in the cache.
+//! Only brokers started with `--subs` learn about new subscriptions, so the
+//! cache is of no use without it.
+
+use std::{collections::HashMap, time::Duration};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use zeromq::ZmqMessage;
+
+/// Most recent update of each topic, with the time it arrived.
+pub struct Cache {
+    entries: HashMap<Bytes, (ZmqMessage, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Remembers `msg` as the last value of its topic, evicting the oldest
+    /// topic if the cache is full.
+    pub fn store(&mut self, msg: &ZmqMessage, now: Instant) {
+        let Some(topic) = topic(msg) else {
+            return;
+        };
+        if !self.entries.contains_key(&topic) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(topic, _)| topic.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            self.entries.insert(topic, (msg.clone(), now));
+        }
+    }
+
+    /// Fresh cached updates whose topic starts with `prefix`.
+    pub fn matching(&mut self, prefix: &str, now: Instant) -> Vec<ZmqMessage> {
+        self.entries.retain(|_, (_, seen)| now - *seen < self.ttl);
+        self.entries
+            .iter()
+            .filter(|(topic, _)| topic.starts_with(prefix.as_bytes()))
+            .map(|(_, (msg, _))| msg.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Topic of an update: its first frame up to and including the first newline.
+fn topic(msg: &ZmqMessage) -> Option<Bytes> {
+    let frame = msg.get(0)?;
+    let end = frame
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(frame.len(), |i| i + 1);
+    Some(frame.slice(..end))
+}
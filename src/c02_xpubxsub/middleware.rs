@@ -0,0 +1,394 @@
+//! Middleware chain of the `c02_xpubxsub` broker.
+//!
+//! A broker started with `--middleware <file>` runs every update through a
+//! chain of stages before forwarding it. Each stage may drop, change or
+//! duplicate the update. The file lists one stage per line, in order:
+//!
+//! ```text
+//! # stage      arguments
+//! rate_limit   5           # at most 5 updates per second for each topic
+//! dedup        100         # drop repeats of the last 100 updates
+//! redact       Humidity    # hide a field
+//! fahrenheit               # convert the temperature
+//! enrich       Area North  # add an `Area: North` line
+//! duplicate    2           # send every update twice
+//! sample       0.5         # keep half of the updates
+//! ```
+//!
+//! The file is read again whenever it changes, without restarting the broker.
+//! Stages whose line did not change keep their state (rate limit windows,
+//! recent updates) across the reload.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, anyhow, bail};
+use bytes::Bytes;
+use rand::Rng;
+use tokio::time::Instant;
+use zeromq::ZmqMessage;
+
+/// How often the broker checks whether the file changed.
+pub const RELOAD_CHECK: Duration = Duration::from_secs(1);
+
+/// A stage of the chain.
+pub trait Middleware: Send {
+    /// Returns the messages to pass on to the next stage: none to drop
+    /// `msg`, several to duplicate it.
+    fn on_message(&mut self, msg: ZmqMessage, now: Instant) -> Vec<ZmqMessage>;
+}
+
+/// First line of an update, e.g. `Update for 00003:`.
+fn topic(msg: &ZmqMessage) -> Bytes {
+    let frame = msg.get(0).cloned().unwrap_or_default();
+    let end = frame
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(frame.len());
+    frame.slice(..end)
+}
+
+/// Rewrites the lines of the update (its first frame) with `f`.
+fn map_lines(msg: ZmqMessage, f: impl Fn(&str) -> String) -> ZmqMessage {
+    let mut frames = msg.into_vec();
+    if let Some(update) = frames.first_mut() {
+        let text = String::from_utf8_lossy(update);
+        let lines: String = text.split_inclusive('\n').map(&f).collect();
+        *update = Bytes::from(lines);
+    }
+    ZmqMessage::try_from(frames).expect("messages always have at least one frame")
+}
+
+/// Lets at most `per_second` updates of each topic through every second.
+struct RateLimit {
+    per_second: u32,
+    windows: HashMap<Bytes, (Instant, u32)>,
+}
+
+impl Middleware for RateLimit {
+    fn on_message(&mut self, msg: ZmqMessage, now: Instant) -> Vec<ZmqMessage> {
+        let (start, count) = self.windows.entry(topic(&msg)).or_insert((now, 0));
+        if now - *start >= Duration::from_secs(1) {
+            (*start, *count) = (now, 0);
+        }
+        *count += 1;
+        match *count <= self.per_second {
+            true => vec![msg],
+            false => vec![],
+        }
+    }
+}
+
+/// Drops the updates identical to one of the last `window` ones.
+struct Dedup {
+    window: usize,
+    recent: VecDeque<Bytes>,
+    seen: HashSet<Bytes>,
+}
+
+impl Middleware for Dedup {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        let update = msg.get(0).cloned().unwrap_or_default();
+        if self.seen.contains(&update) {
+            return vec![];
+        }
+        self.seen.insert(update.clone());
+        self.recent.push_back(update);
+        if self.recent.len() > self.window {
+            let oldest = self.recent.pop_front().expect("not empty");
+            self.seen.remove(&oldest);
+        }
+        vec![msg]
+    }
+}
+
+/// Hides the value of a field, e.g. `  Humidity: 23%.`.
+struct Redact {
+    field: String,
+}
+
+impl Middleware for Redact {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        let prefix = format!("{}:", self.field);
+        vec![map_lines(msg, |line| {
+            match line.trim_start().starts_with(&prefix) {
+                true => format!("  {prefix} [redacted]\n"),
+                false => line.to_string(),
+            }
+        })]
+    }
+}
+
+/// Converts `Temperature: 24ºC` into `Temperature: 75.2ºF`.
+struct Fahrenheit;
+
+impl Middleware for Fahrenheit {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        vec![map_lines(msg, |line| {
+            let celsius = line
+                .trim()
+                .strip_prefix("Temperature:")
+                .and_then(|t| t.trim().strip_suffix("ºC").or(t.trim().strip_suffix("°C")))
+                .and_then(|t| t.parse::<f64>().ok());
+            match celsius {
+                Some(c) => format!("  Temperature: {:.1}ºF\n", c * 9.0 / 5.0 + 32.0),
+                None => line.to_string(),
+            }
+        })]
+    }
+}
+
+/// Adds a `field: value` line to the end of the update.
+struct Enrich {
+    field: String,
+    value: String,
+}
+
+impl Middleware for Enrich {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        let mut frames = msg.into_vec();
+        if let Some(update) = frames.first_mut() {
+            let mut text = String::from_utf8_lossy(update).to_string();
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&format!("  {}: {}\n", self.field, self.value));
+            *update = Bytes::from(text);
+        }
+        vec![ZmqMessage::try_from(frames).expect("messages always have at least one frame")]
+    }
+}
+
+/// Passes each update on `copies` times.
+struct Duplicate {
+    copies: usize,
+}
+
+impl Middleware for Duplicate {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        vec![msg; self.copies]
+    }
+}
+
+/// Keeps each update with probability `rate`.
+struct Sample {
+    rate: f64,
+}
+
+impl Middleware for Sample {
+    fn on_message(&mut self, msg: ZmqMessage, _: Instant) -> Vec<ZmqMessage> {
+        match rand::rng().random_bool(self.rate) {
+            true => vec![msg],
+            false => vec![],
+        }
+    }
+}
+
+/// Parses the argument of the stage `name`, described as `what` in errors.
+fn number<T>(name: &str, arg: Option<&str>, what: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    arg.ok_or(anyhow!("`{name}` needs {what}"))?
+        .parse()
+        .with_context(|| format!("`{name}` needs {what}"))
+}
+
+fn parse_stage(line: &str) -> anyhow::Result<Box<dyn Middleware>> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let arg = words.next();
+    Ok(match name {
+        "rate_limit" => Box::new(RateLimit {
+            per_second: number(name, arg, "a whole number of updates per second")?,
+            windows: HashMap::new(),
+        }),
+        "dedup" => Box::new(Dedup {
+            window: number(name, arg, "a whole number of updates")?,
+            recent: VecDeque::new(),
+            seen: HashSet::new(),
+        }),
+        "redact" => Box::new(Redact {
+            field: arg.ok_or(anyhow!("`redact` needs a field"))?.to_string(),
+        }),
+        "fahrenheit" => Box::new(Fahrenheit),
+        "enrich" => {
+            let value = words.collect::<Vec<_>>().join(" ");
+            let (Some(field), false) = (arg, value.is_empty()) else {
+                bail!("`enrich` needs a field and a value");
+            };
+            Box::new(Enrich {
+                field: field.to_string(),
+                value,
+            })
+        }
+        "duplicate" => Box::new(Duplicate {
+            copies: number(name, arg, "a whole number of copies")?,
+        }),
+        "sample" => {
+            let rate: f64 = number(name, arg, "a rate between 0 and 1")?;
+            if !(0.0..=1.0).contains(&rate) {
+                bail!("`sample` needs a rate between 0 and 1");
+            }
+            Box::new(Sample { rate })
+        }
+        other => bail!("unknown stage `{other}`"),
+    })
+}
+
+/// The stages of the file, each with its line (comment and extra spaces removed).
+fn parse_chain(text: &str) -> anyhow::Result<Vec<(String, Box<dyn Middleware>)>> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| {
+            let line = line.split('#').next().unwrap_or_default();
+            (n, line.split_whitespace().collect::<Vec<_>>().join(" "))
+        })
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| {
+            let stage = parse_stage(&line).with_context(|| format!("line {}", n + 1))?;
+            Ok((line, stage))
+        })
+        .collect()
+}
+
+/// The stages read from the middleware file.
+pub struct Chain {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    stages: Vec<(String, Box<dyn Middleware>)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Chain {
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let modified = modified(&path);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let stages = parse_chain(&text).with_context(|| format!("in {}", path.display()))?;
+        println!("Loaded {} middleware stages", stages.len());
+        Ok(Self {
+            path,
+            modified,
+            stages,
+        })
+    }
+
+    /// Loads the file again if it changed; keeps the current chain if the
+    /// new one has errors, and the current stages whose line is the same.
+    pub fn reload_if_changed(&mut self) {
+        if modified(&self.path) == self.modified {
+            return;
+        }
+        match Self::load(self.path.clone()) {
+            Ok(mut chain) => {
+                let mut previous = std::mem::take(&mut self.stages);
+                let mut kept = 0;
+                for (line, stage) in &mut chain.stages {
+                    if let Some(i) = previous.iter().position(|(old, _)| old == line) {
+                        *stage = previous.remove(i).1;
+                        kept += 1;
+                    }
+                }
+                println!("Kept the state of {kept} middleware stages");
+                *self = chain;
+            }
+            Err(e) => {
+                eprintln!("Error: {e:#}; keeping the previous middleware");
+                self.modified = modified(&self.path);
+            }
+        }
+    }
+
+    /// Runs `msg` through every stage.
+    pub fn run(&mut self, msg: ZmqMessage, now: Instant) -> Vec<ZmqMessage> {
+        let mut messages = vec![msg];
+        for (_, stage) in &mut self.stages {
+            messages = messages
+                .into_iter()
+                .flat_map(|msg| stage.on_message(msg, now))
+                .collect();
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(text: &str) -> ZmqMessage {
+        ZmqMessage::from(text.to_string())
+    }
+
+    fn text(msg: &ZmqMessage) -> String {
+        String::from_utf8_lossy(msg.get(0).expect("one frame")).to_string()
+    }
+
+    #[test]
+    fn enrich_adds_a_field() {
+        let mut stage = parse_stage("enrich Area North East").expect("valid stage");
+        let out = stage.on_message(
+            update("Update for 00001:\n  Humidity: 3%.\n"),
+            Instant::now(),
+        );
+        assert_eq!(
+            text(&out[0]),
+            "Update for 00001:\n  Humidity: 3%.\n  Area: North East\n"
+        );
+        assert!(parse_stage("enrich Area").is_err());
+    }
+
+    #[test]
+    fn duplicate_copies_updates() {
+        let mut stage = parse_stage("duplicate 3").expect("valid stage");
+        let out = stage.on_message(update("Update for 00001:\n"), Instant::now());
+        assert_eq!(out.len(), 3);
+        assert!(out.iter().all(|msg| text(msg) == "Update for 00001:\n"));
+    }
+
+    #[test]
+    fn counts_must_be_whole_numbers() {
+        for line in [
+            "rate_limit -5",
+            "rate_limit 2.7",
+            "dedup 1e30",
+            "duplicate -1",
+        ] {
+            assert!(parse_stage(line).is_err(), "{line} was accepted");
+        }
+        assert!(parse_stage("rate_limit 5").is_ok());
+        assert!(parse_stage("sample 0.5").is_ok());
+    }
+
+    #[test]
+    fn reload_keeps_the_state_of_unchanged_stages() {
+        let path = std::env::temp_dir().join(format!("middleware-{}.txt", std::process::id()));
+        std::fs::write(&path, "dedup 10\nrate_limit 100\n").expect("writable temp dir");
+        let mut chain = Chain::load(path.clone()).expect("valid chain");
+        let now = Instant::now();
+        assert_eq!(chain.run(update("Update for 00001:\n"), now).len(), 1);
+
+        // Same dedup line, with another comment and spacing: it remembers the update
+        std::fs::write(&path, "dedup   10  # still\nduplicate 2\n").expect("writable temp dir");
+        chain.modified = None;
+        chain.reload_if_changed();
+        assert_eq!(chain.run(update("Update for 00001:\n"), now).len(), 0);
+        assert_eq!(chain.run(update("Update for 00002:\n"), now).len(), 2);
+
+        // A changed dedup line starts over
+        std::fs::write(&path, "dedup 20\n").expect("writable temp dir");
+        chain.modified = None;
+        chain.reload_if_changed();
+        assert_eq!(chain.run(update("Update for 00001:\n"), now).len(), 1);
+        _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,228 @@
+//! Subscription forwarding for the `c02_xpubxsub` broker.
+//!
+//! A real XPUB/XSUB proxy reads the subscribe/unsubscribe frames of its
+//! subscribers and sends them upstream, so publishers only send what somebody
+//! wants. The `zeromq` crate has no XPUB/XSUB sockets (its PUB socket keeps
+//! subscriptions to itself), so subscribers started with `--subs <addr>`
+//! also send their subscriptions to a ROUTER socket of the broker, using the
+//! same frames as ZMTP: `\x01topic` to subscribe and `\x00topic` to
+//! unsubscribe. The broker counts the subscribers of each topic and
+//! subscribes its SUB socket upstream only while that count is not zero.
+//!
+//! Subscribers repeat their subscriptions every `KEEPALIVE`, so the
+//! subscriptions of a subscriber that died are dropped after `EXPIRY`.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use zeromq::{ZmqMessage, prelude::*};
+
+use super::acl;
+
+/// Time between two repetitions of the subscriptions of a subscriber.
+pub const KEEPALIVE: Duration = Duration::from_secs(2);
+/// Time after which a subscription that was not repeated is dropped.
+const EXPIRY: Duration = Duration::from_secs(6);
+
+pub const SUBSCRIBE: u8 = 1;
+pub const UNSUBSCRIBE: u8 = 0;
+/// Sent back to a subscriber whose subscription was refused (see `acl`).
+pub const DENIED: u8 = 2;
+/// Proves the identity of a subscriber (see `acl`).
+pub const HANDSHAKE: u8 = 3;
+
+/// A change in the subscriptions of the broker that must go upstream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Upstream {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+/// Outcome of a subscription message.
+#[derive(Debug, Default)]
+pub struct Handled {
+    /// Topic a subscriber just subscribed to (not a keepalive).
+    pub subscribed: Option<String>,
+    /// Change to send upstream, if any.
+    pub upstream: Option<Upstream>,
+}
+
+/// Subscribers of each topic, with the last time they confirmed it.
+#[derive(Default)]
+pub struct Subscriptions {
+    topics: HashMap<String, HashMap<Bytes, Instant>>,
+}
+
+impl Subscriptions {
+    /// Handles a `[subscriber, "\x01topic" | "\x00topic"]` message received
+    /// on the subscriptions ROUTER socket.
+    pub fn handle(&mut self, msg: ZmqMessage, now: Instant) -> Handled {
+        let frames = msg.into_vec();
+        let [subscriber, frame] = frames.as_slice() else {
+            eprintln!("ERROR: Malformed subscription message");
+            return Handled::default();
+        };
+        let Some((&kind, topic)) = frame.split_first() else {
+            return Handled::default();
+        };
+        let topic = String::from_utf8_lossy(topic).to_string();
+        match kind {
+            SUBSCRIBE => self.subscribe(subscriber.clone(), topic, now),
+            UNSUBSCRIBE => Handled {
+                subscribed: None,
+                upstream: self.unsubscribe(subscriber, topic),
+            },
+            _ => Handled::default(),
+        }
+    }
+
+    fn subscribe(&mut self, subscriber: Bytes, topic: String, now: Instant) -> Handled {
+        let subscribers = self.topics.entry(topic.clone()).or_default();
+        if subscribers.insert(subscriber, now).is_some() {
+            return Handled::default(); // just a keepalive
+        }
+        println!("Subscribe {topic:?} (refcount {})", subscribers.len());
+        Handled {
+            upstream: (subscribers.len() == 1).then(|| Upstream::Subscribe(topic.clone())),
+            subscribed: Some(topic),
+        }
+    }
+
+    fn unsubscribe(&mut self, subscriber: &Bytes, topic: String) -> Option<Upstream> {
+        let subscribers = self.topics.get_mut(&topic)?;
+        subscribers.remove(subscriber)?;
+        println!("Unsubscribe {topic:?} (refcount {})", subscribers.len());
+        if !subscribers.is_empty() {
+            return None;
+        }
+        self.topics.remove(&topic);
+        Some(Upstream::Unsubscribe(topic))
+    }
+
+    /// Drops the subscriptions that were not confirmed in time.
+    pub fn expire(&mut self, now: Instant) -> Vec<Upstream> {
+        let mut upstream = Vec::new();
+        for (topic, subscribers) in self.topics.iter_mut() {
+            let before = subscribers.len();
+            subscribers.retain(|_, seen| now - *seen < EXPIRY);
+            if subscribers.len() < before {
+                println!(
+                    "Expired {} subscribers of {topic:?} (refcount {})",
+                    before - subscribers.len(),
+                    subscribers.len()
+                );
+            }
+            if subscribers.is_empty() {
+                upstream.push(Upstream::Unsubscribe(topic.clone()));
+            }
+        }
+        self.topics.retain(|_, subscribers| !subscribers.is_empty());
+        upstream
+    }
+
+    /// Subscribers of the topics `update` starts with.
+    pub fn subscribers_of(&self, update: &[u8]) -> BTreeSet<Bytes> {
+        self.topics
+            .iter()
+            .filter(|(topic, _)| update.starts_with(topic.as_bytes()))
+            .flat_map(|(_, subscribers)| subscribers.keys().cloned())
+            .collect()
+    }
+
+    /// Number of subscribers of each topic.
+    pub fn refcounts(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.topics.iter().map(|(t, s)| (t.as_str(), s.len()))
+    }
+}
+
+/// Applies a change of subscriptions to the upstream SUB socket, and to the
+/// parent broker if there is one.
+pub async fn forward(
+    backend: &mut zeromq::SubSocket,
+    parent: &mut Option<Announcer>,
+    change: Upstream,
+) -> anyhow::Result<()> {
+    if let Some(parent) = parent {
+        parent.announce(&change).await?;
+    }
+    match change {
+        Upstream::Subscribe(topic) => backend.subscribe(&topic).await?,
+        Upstream::Unsubscribe(topic) => backend.unsubscribe(&topic).await?,
+    }
+    Ok(())
+}
+
+/// Subscriber side: announces topics to the subscriptions socket of a broker.
+/// Brokers in a tree use it too, to announce their topics to their parent.
+pub struct Announcer {
+    sock: zeromq::DealerSocket,
+    topics: BTreeSet<String>,
+}
+
+impl Announcer {
+    pub async fn connect(addr: SocketAddr) -> anyhow::Result<Self> {
+        let mut sock = zeromq::DealerSocket::new();
+        sock.connect(format!("tcp://{addr}").as_str()).await?;
+        Ok(Self {
+            sock,
+            topics: BTreeSet::new(),
+        })
+    }
+
+    pub async fn subscribe(&mut self, topic: &str) -> anyhow::Result<()> {
+        self.topics.insert(topic.to_string());
+        self.send(SUBSCRIBE, topic).await
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> anyhow::Result<()> {
+        self.topics.remove(topic);
+        self.send(UNSUBSCRIBE, topic).await
+    }
+
+    /// Announces the same change as `forward`.
+    pub async fn announce(&mut self, change: &Upstream) -> anyhow::Result<()> {
+        match change {
+            Upstream::Subscribe(topic) => self.subscribe(topic).await,
+            Upstream::Unsubscribe(topic) => self.unsubscribe(topic).await,
+        }
+    }
+
+    /// Proves to the broker who this subscriber is (see `acl`).
+    pub async fn handshake(&mut self, name: &str, secret: &str) -> anyhow::Result<()> {
+        self.sock.send(acl::handshake(name, secret).into()).await?;
+        Ok(())
+    }
+
+    /// Waits for an update or a denial sent on this socket by a broker with a policy.
+    pub async fn recv(&mut self) -> anyhow::Result<ZmqMessage> {
+        Ok(self.sock.recv().await?)
+    }
+
+    /// Repeats the subscriptions, so the broker does not expire them.
+    pub async fn keepalive(&mut self) -> anyhow::Result<()> {
+        for topic in self.topics.clone() {
+            self.send(SUBSCRIBE, &topic).await?;
+        }
+        Ok(())
+    }
+
+    /// Withdraws all the subscriptions.
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        for topic in std::mem::take(&mut self.topics) {
+            self.send(UNSUBSCRIBE, &topic).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, kind: u8, topic: &str) -> anyhow::Result<()> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(topic.as_bytes());
+        self.sock.send(frame.into()).await?;
+        Ok(())
+    }
+}
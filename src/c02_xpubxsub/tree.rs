@@ -0,0 +1,119 @@
+//! Broker trees for the `c02_xpubxsub` example.
+//!
+//! A broker started with `--upstream <addr>` also subscribes to the frontend
+//! of a parent broker, so updates flow down the tree. With
+//! `--upstream-subs <addr>` it announces its own subscriptions to the parent
+//! (see `subscriptions`), so they propagate up the tree as well.
+//!
+//! Every broker appends a `name@ms` hop (its name and the arrival time, in
+//! milliseconds since the Unix epoch) to a trace frame after the update,
+//! which starts with a `\x04` byte so that it is never mistaken for another
+//! frame, such as the signature of a publisher. A broker that finds its own name in the trace drops the update, which
+//! breaks cycles, and subscribers run with `--hops` print the latency of
+//! each hop.
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use zeromq::ZmqMessage;
+
+use crate::clock::now_ms;
+
+/// First byte of the trace frame.
+const TRACE: u8 = 4;
+
+/// Position of a broker in a tree.
+pub struct Hop {
+    /// Name written in the trace; must be unique in the tree.
+    pub name: String,
+    /// Frontend of the parent broker.
+    pub parent: Option<SocketAddr>,
+    /// Subscriptions socket of the parent broker.
+    pub parent_subs: Option<SocketAddr>,
+}
+
+fn is_trace(frame: &Bytes) -> bool {
+    frame.first() == Some(&TRACE)
+}
+
+/// Hops of the trace frame of `msg`, oldest first.
+pub fn hops(msg: &ZmqMessage) -> Vec<(String, u64)> {
+    let Some(trace) = msg.iter().skip(1).find(|frame| is_trace(frame)) else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&trace[1..])
+        .split(',')
+        .filter_map(|hop| hop.rsplit_once('@'))
+        .filter_map(|(name, ms)| Some((name.to_string(), ms.parse().ok()?)))
+        .collect()
+}
+
+/// Adds this broker to the trace of `msg`, or returns `None` if the update
+/// already went through it.
+pub fn stamp(msg: ZmqMessage, name: &str) -> Option<ZmqMessage> {
+    if hops(&msg).iter().any(|(hop, _)| hop == name) {
+        return None;
+    }
+    let mut frames = msg.into_vec();
+    let hop = format!("{name}@{}", now_ms());
+    match frames.iter_mut().skip(1).find(|frame| is_trace(frame)) {
+        Some(trace) => *trace = [trace.as_ref(), b",", hop.as_bytes()].concat().into(),
+        None => frames.push([&[TRACE], hop.as_bytes()].concat().into()),
+    }
+    Some(ZmqMessage::try_from(frames).expect("messages always have at least one frame"))
+}
+
+/// `msg` without its trace frame.
+pub fn unstamped(msg: &ZmqMessage) -> ZmqMessage {
+    let frames: Vec<Bytes> = msg
+        .iter()
+        .filter(|frame| !is_trace(frame))
+        .cloned()
+        .collect();
+    ZmqMessage::try_from(frames).expect("the update is not a trace frame")
+}
+
+/// Describes the hops of `msg` with the time each one took, e.g.
+/// `A (+0ms) -> B (+2ms) -> subscriber (+1ms)`.
+pub fn describe(msg: &ZmqMessage) -> String {
+    let mut hops = hops(msg);
+    hops.push(("subscriber".to_string(), now_ms()));
+    let mut previous = hops[0].1;
+    hops.iter()
+        .map(|(name, ms)| {
+            let latency = ms.saturating_sub(previous);
+            previous = *ms;
+            format!("{name} (+{latency}ms)")
+        })
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_append_to_the_trace_frame() {
+        let msg = stamp(ZmqMessage::from("Update for 00001:\n"), "A").expect("new hop");
+        let msg = stamp(msg, "B").expect("new hop");
+        assert_eq!(msg.len(), 2);
+        let names: Vec<String> = hops(&msg).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["A", "B"]);
+        assert!(stamp(msg, "A").is_none());
+    }
+
+    #[test]
+    fn signatures_are_not_mistaken_for_traces() {
+        let mut msg = ZmqMessage::from("Update for 00001:\n");
+        msg.push_back(Bytes::from("porto:1700000000000000:abcd@12"));
+        assert!(hops(&msg).is_empty());
+        let msg = stamp(msg, "A").expect("new hop");
+        assert_eq!(msg.len(), 3);
+        assert_eq!(
+            msg.get(1).expect("signature"),
+            "porto:1700000000000000:abcd@12"
+        );
+        assert_eq!(hops(&msg)[0].0, "A");
+    }
+}
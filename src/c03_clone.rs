@@ -0,0 +1,463 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use clap::{CommandFactory, Parser};
+use tokio::time::{Instant, interval, timeout};
+use zeromq::{ZmqMessage, prelude::*};
+
+mod kvmsg;
+
+use kvmsg::{KvMsg, SNAPSHOT_END, SNAPSHOT_REQUEST};
+
+/// How often the server looks for expired keys.
+const TTL_CHECK: Duration = Duration::from_secs(1);
+/// How long `set` waits to see its own update published.
+const SET_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, clap::Subcommand)]
+enum Mode {
+    /// Run the server, specifying the snapshot addr.
+    /// Updates are published on the next port and collected on the one after.
+    Server { addr: SocketAddr },
+    /// Print the value of a key.
+    Get { addr: SocketAddr, key: String },
+    /// Set a key; an empty value deletes it.
+    Set {
+        addr: SocketAddr,
+        key: String,
+        value: String,
+        /// Delete the key after this many seconds.
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// Print the keys under a prefix, then every change to them.
+    Watch {
+        addr: SocketAddr,
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+}
+
+#[derive(clap::Parser)]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Mode,
+}
+
+/// The arguments of the example, for `sdle_class list`.
+pub fn command() -> clap::Command {
+    Cli::command()
+}
+
+pub fn main<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async { main_impl(args).await })
+}
+
+pub async fn main_impl<'a, I: IntoIterator<Item = &'a String>>(args: I) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(args);
+
+    match cli.cmd {
+        Mode::Server { addr } => server_handler(addr).await,
+        Mode::Get { addr, key } => get_handler(addr, key).await,
+        Mode::Set {
+            addr,
+            key,
+            value,
+            ttl,
+        } => set_handler(addr, key, value, ttl.map(Duration::from_secs)).await,
+        Mode::Watch { addr, prefix } => watch_handler(addr, prefix).await,
+    }
+}
+
+/// The PUB socket of the server lives on the port after the snapshot one.
+fn updates_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip(), addr.port() + 1)
+}
+
+/// The PULL socket of the server lives two ports after the snapshot one.
+fn collector_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip(), addr.port() + 2)
+}
+
+/// A key held by the server.
+struct Entry {
+    kv: KvMsg,
+    expires: Option<Instant>,
+}
+
+/// The authoritative map of the server, and the sequence of its last change.
+#[derive(Default)]
+struct Store {
+    map: BTreeMap<String, Entry>,
+    seq: u64,
+}
+
+impl Store {
+    /// Numbers a change collected from a client and applies it.
+    fn apply(&mut self, mut kv: KvMsg, now: Instant) -> KvMsg {
+        self.seq += 1;
+        kv.seq = self.seq;
+        match kv.is_delete() {
+            true => {
+                self.map.remove(&kv.key);
+            }
+            false => {
+                let expires = (kv.ttl_ms > 0).then(|| now + Duration::from_millis(kv.ttl_ms));
+                let entry = Entry {
+                    kv: kv.clone(),
+                    expires,
+                };
+                self.map.insert(kv.key.clone(), entry);
+            }
+        }
+        kv
+    }
+
+    /// Removes the keys whose TTL ran out, and numbers their deletes.
+    fn expire(&mut self, now: Instant) -> Vec<KvMsg> {
+        let expired: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(_, e)| e.expires.is_some_and(|t| t <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .into_iter()
+            .map(|key| self.apply(KvMsg::new(key, Bytes::new()), now))
+            .collect()
+    }
+
+    /// The live entries under `subtree`, with the time they have left, then
+    /// the end marker with the sequence of the snapshot.
+    fn snapshot(&self, subtree: &str, now: Instant) -> Vec<KvMsg> {
+        let mut snapshot = Vec::new();
+        for entry in self.map.values().filter(|e| e.kv.key.starts_with(subtree)) {
+            let mut kv = entry.kv.clone();
+            if let Some(expires) = entry.expires {
+                // Expired but not removed yet; a ttl of 0 would mean it never expires
+                if expires <= now {
+                    continue;
+                }
+                kv.ttl_ms = ((expires - now).as_millis() as u64).max(1);
+            }
+            snapshot.push(kv);
+        }
+        snapshot.push(KvMsg {
+            seq: self.seq,
+            ..KvMsg::new(SNAPSHOT_END, subtree.to_string())
+        });
+        snapshot
+    }
+}
+
+/// Server code.
+/// Holds the authoritative map: answers snapshot requests on a ROUTER socket,
+/// numbers the changes collected on a PULL socket and publishes them on a
+/// PUB socket.
+async fn server_handler(addr: SocketAddr) -> anyhow::Result<()> {
+    let mut snapshot = zeromq::RouterSocket::new();
+    snapshot.bind(format!("tcp://{addr}").as_str()).await?;
+    let mut publisher = zeromq::PubSocket::new();
+    publisher
+        .bind(format!("tcp://{}", updates_addr(addr)).as_str())
+        .await?;
+    let mut collector = zeromq::PullSocket::new();
+    collector
+        .bind(format!("tcp://{}", collector_addr(addr)).as_str())
+        .await?;
+
+    let mut store = Store::default();
+    let mut ttl_check = interval(TTL_CHECK);
+    loop {
+        tokio::select! {
+            msg = snapshot.recv() => {
+                // [client, "ICANHAZ?", 0, 0, subtree]
+                let msg = msg?;
+                let (Some(client), Some(request)) = (msg.get(0).cloned(), KvMsg::decode(&msg)) else {
+                    eprintln!("ERROR: Malformed snapshot request");
+                    continue;
+                };
+                if request.key != SNAPSHOT_REQUEST {
+                    eprintln!("ERROR: Unknown request {:?}", request.key);
+                    continue;
+                }
+                let subtree = String::from_utf8_lossy(&request.value).to_string();
+                let kvs = store.snapshot(&subtree, Instant::now());
+                // The client may be gone already: that must not stop the server.
+                match send_snapshot(&mut snapshot, &client, kvs).await {
+                    Ok(()) => println!("Sent snapshot of {subtree:?} at sequence {}", store.seq),
+                    Err(e) => eprintln!("ERROR: Snapshot of {subtree:?} not sent: {e}"),
+                }
+            }
+            msg = collector.recv() => {
+                let Some(kv) = KvMsg::decode(&msg?) else {
+                    eprintln!("ERROR: Malformed update");
+                    continue;
+                };
+                let kv = store.apply(kv, Instant::now());
+                println!("Update {}: {:?}", kv.seq, kv.key);
+                publisher.send(kv.encode()).await?;
+            }
+            _ = ttl_check.tick() => {
+                for kv in store.expire(Instant::now()) {
+                    println!("Update {}: {:?} expired", kv.seq, kv.key);
+                    publisher.send(kv.encode()).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Sends a snapshot to `client`, one message per key.
+async fn send_snapshot(
+    snapshot: &mut zeromq::RouterSocket,
+    client: &Bytes,
+    kvs: Vec<KvMsg>,
+) -> anyhow::Result<()> {
+    for kv in kvs {
+        snapshot.send(envelope(client, &kv)).await?;
+    }
+    Ok(())
+}
+
+fn envelope(client: &Bytes, kv: &KvMsg) -> ZmqMessage {
+    let mut frames = vec![client.clone()];
+    frames.extend(kv.frames());
+    ZmqMessage::try_from(frames).expect("messages always have at least one frame")
+}
+
+/// A copy of the keys under a prefix, and the sequence of the last change
+/// it has.
+#[derive(Default)]
+struct Replica {
+    map: BTreeMap<String, KvMsg>,
+    seq: u64,
+}
+
+impl Replica {
+    /// Applies an update, unless it is not newer than what the replica has
+    /// (e.g. already in the snapshot); returns whether it was applied.
+    fn apply(&mut self, kv: &KvMsg) -> bool {
+        if kv.seq <= self.seq {
+            return false;
+        }
+        self.seq = kv.seq;
+        match kv.is_delete() {
+            true => self.map.remove(&kv.key),
+            false => self.map.insert(kv.key.clone(), kv.clone()),
+        };
+        true
+    }
+}
+
+/// Fetches the keys under `subtree`, at the sequence of that snapshot.
+async fn snapshot(addr: SocketAddr, subtree: &str) -> anyhow::Result<Replica> {
+    let mut sock = zeromq::DealerSocket::new();
+    sock.connect(format!("tcp://{addr}").as_str()).await?;
+    sock.send(KvMsg::new(SNAPSHOT_REQUEST, subtree.to_string()).encode())
+        .await?;
+
+    let mut replica = Replica::default();
+    loop {
+        let kv = KvMsg::decode(&sock.recv().await?).ok_or(anyhow!("Malformed snapshot"))?;
+        if kv.key == SNAPSHOT_END {
+            replica.seq = kv.seq;
+            return Ok(replica);
+        }
+        replica.map.insert(kv.key.clone(), kv);
+    }
+}
+
+fn show(kv: &KvMsg) {
+    match kv.is_delete() {
+        true => println!("[{}] {} deleted", kv.seq, kv.key),
+        false => println!(
+            "[{}] {}={}",
+            kv.seq,
+            kv.key,
+            String::from_utf8_lossy(&kv.value)
+        ),
+    }
+}
+
+/// Client code for `get`.
+async fn get_handler(addr: SocketAddr, key: String) -> anyhow::Result<()> {
+    let replica = snapshot(addr, &key).await?;
+    match replica.map.get(&key) {
+        Some(kv) => show(kv),
+        None => println!("{key} is not set"),
+    }
+    Ok(())
+}
+
+/// Client code for `set`.
+/// Pushes the change to the server and waits until it is published, to
+/// report its sequence.
+async fn set_handler(
+    addr: SocketAddr,
+    key: String,
+    value: String,
+    ttl: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut updates = zeromq::SubSocket::new();
+    updates.subscribe(&key).await?;
+    updates
+        .connect(format!("tcp://{}", updates_addr(addr)).as_str())
+        .await?;
+    let mut collector = zeromq::PushSocket::new();
+    collector
+        .connect(format!("tcp://{}", collector_addr(addr)).as_str())
+        .await?;
+    // The server takes a while to learn about the subscription: a round-trip
+    // to it first, so the update is not published before it does.
+    snapshot(addr, &key).await?;
+
+    let kv = KvMsg {
+        ttl_ms: ttl.map_or(0, |ttl| ttl.as_millis() as u64),
+        ..KvMsg::new(key, value)
+    };
+    collector.send(kv.encode()).await?;
+    timeout(SET_TIMEOUT, async {
+        loop {
+            match KvMsg::decode(&updates.recv().await?) {
+                Some(update) if update.key == kv.key && update.value == kv.value => {
+                    show(&update);
+                    return anyhow::Ok(());
+                }
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("No confirmation from the server"))?
+}
+
+/// Client code for `watch`.
+/// Subscribes to the updates first, so none is lost while the snapshot is
+/// fetched, then skips those already included in the snapshot.
+async fn watch_handler(addr: SocketAddr, prefix: String) -> anyhow::Result<()> {
+    let mut updates = zeromq::SubSocket::new();
+    updates.subscribe(&prefix).await?;
+    updates
+        .connect(format!("tcp://{}", updates_addr(addr)).as_str())
+        .await?;
+
+    let mut replica = snapshot(addr, &prefix).await?;
+    println!("Snapshot of {prefix:?} at sequence {}:", replica.seq);
+    replica.map.values().for_each(show);
+
+    loop {
+        let Some(kv) = KvMsg::decode(&updates.recv().await?) else {
+            eprintln!("ERROR: Malformed update");
+            continue;
+        };
+        if replica.apply(&kv) {
+            show(&kv);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(store: &mut Store, key: &str, value: &str, now: Instant) -> KvMsg {
+        store.apply(KvMsg::new(key, value.to_string()), now)
+    }
+
+    /// What a client gets from `store.snapshot`, as `snapshot` builds it.
+    fn replica(store: &Store, subtree: &str, now: Instant) -> Replica {
+        let mut kvs = store.snapshot(subtree, now);
+        let end = kvs.pop().expect("end marker");
+        assert_eq!(end.key, SNAPSHOT_END);
+        Replica {
+            map: kvs.into_iter().map(|kv| (kv.key.clone(), kv)).collect(),
+            seq: end.seq,
+        }
+    }
+
+    #[test]
+    fn updates_after_the_snapshot_are_applied_in_order() {
+        let now = Instant::now();
+        let mut store = Store::default();
+        let early = set(&mut store, "a", "1", now);
+        let mut replica = replica(&store, "", now);
+        assert_eq!(replica.seq, 1);
+
+        let b = set(&mut store, "b", "2", now);
+        let a = set(&mut store, "a", "3", now);
+        let delete = set(&mut store, "b", "", now);
+        // The subscription saw the update already in the snapshot too
+        assert!(!replica.apply(&early));
+        assert!(replica.apply(&b));
+        assert!(replica.apply(&a));
+        assert!(replica.apply(&delete));
+        assert_eq!(replica.seq, 4);
+        assert_eq!(replica.map.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(replica.map["a"].value, "3");
+    }
+
+    #[test]
+    fn updates_older_than_the_snapshot_are_discarded() {
+        let now = Instant::now();
+        let mut store = Store::default();
+        let old = set(&mut store, "a", "1", now);
+        let older = set(&mut store, "a", "2", now);
+        set(&mut store, "a", "3", now);
+        let mut replica = replica(&store, "", now);
+
+        assert!(!replica.apply(&old));
+        assert!(!replica.apply(&older));
+        assert_eq!(replica.map["a"].value, "3");
+        assert_eq!(replica.seq, 3);
+    }
+
+    #[test]
+    fn snapshots_only_have_the_subtree() {
+        let now = Instant::now();
+        let mut store = Store::default();
+        set(&mut store, "porto/temp", "18", now);
+        set(&mut store, "lisboa/temp", "21", now);
+        let replica = replica(&store, "porto/", now);
+        assert_eq!(replica.map.keys().collect::<Vec<_>>(), ["porto/temp"]);
+        assert_eq!(replica.seq, 2);
+    }
+
+    #[test]
+    fn keys_expire_after_their_ttl() {
+        let now = Instant::now();
+        let mut store = Store::default();
+        let kv = KvMsg {
+            ttl_ms: 1000,
+            ..KvMsg::new("a", "1")
+        };
+        store.apply(kv, now);
+
+        let later = now + Duration::from_millis(400);
+        assert!(store.expire(later).is_empty());
+        let snapshot = store.snapshot("", later);
+        assert_eq!(snapshot[0].ttl_ms, 600);
+
+        // Expired, but the server did not look yet
+        let expired = now + Duration::from_millis(1000);
+        assert_eq!(store.snapshot("", expired).len(), 1);
+        let deletes = store.expire(expired);
+        assert_eq!(deletes.len(), 1);
+        assert!(deletes[0].is_delete());
+        assert_eq!((deletes[0].key.as_str(), deletes[0].seq), ("a", 2));
+        assert!(store.map.is_empty());
+    }
+
+    #[test]
+    fn keys_without_ttl_never_expire() {
+        let now = Instant::now();
+        let mut store = Store::default();
+        set(&mut store, "a", "1", now);
+        assert!(store.expire(now + Duration::from_secs(3600)).is_empty());
+        assert_eq!(store.snapshot("", now)[0].ttl_ms, 0);
+    }
+}
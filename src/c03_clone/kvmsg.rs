@@ -0,0 +1,100 @@
+//! Key-value messages of the `c03_clone` example.
+//!
+//! Every message has four frames: `[key, sequence, ttl, value]`.
+//! The key comes first so SUB sockets can filter updates by key prefix.
+//! The sequence is set by the server (clients send 0), the TTL is in
+//! milliseconds (0 means the key never expires) and an empty value deletes
+//! the key.
+
+use bytes::Bytes;
+use zeromq::ZmqMessage;
+
+/// Key of the message a client sends to ask for a snapshot.
+pub const SNAPSHOT_REQUEST: &str = "ICANHAZ?";
+/// Key of the message that ends a snapshot; its sequence is the one of the
+/// snapshot.
+pub const SNAPSHOT_END: &str = "KTHXBAI";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvMsg {
+    pub key: String,
+    pub seq: u64,
+    pub ttl_ms: u64,
+    pub value: Bytes,
+}
+
+impl KvMsg {
+    pub fn new(key: impl Into<String>, value: impl Into<Bytes>) -> Self {
+        Self {
+            key: key.into(),
+            seq: 0,
+            ttl_ms: 0,
+            value: value.into(),
+        }
+    }
+
+    /// Whether this update deletes its key.
+    pub fn is_delete(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Parses the last four frames of `msg`, so ROUTER envelopes are ignored.
+    pub fn decode(msg: &ZmqMessage) -> Option<Self> {
+        let frames: Vec<_> = msg.iter().collect();
+        let [key, seq, ttl_ms, value] = frames[frames.len().checked_sub(4)?..] else {
+            return None;
+        };
+        Some(Self {
+            key: String::from_utf8(key.to_vec()).ok()?,
+            seq: std::str::from_utf8(seq).ok()?.parse().ok()?,
+            ttl_ms: std::str::from_utf8(ttl_ms).ok()?.parse().ok()?,
+            value: value.clone(),
+        })
+    }
+
+    pub fn encode(&self) -> ZmqMessage {
+        self.frames().try_into().expect("four frames")
+    }
+
+    /// The frames of the message, to be prefixed with an envelope.
+    pub fn frames(&self) -> Vec<Bytes> {
+        vec![
+            Bytes::from(self.key.clone()),
+            Bytes::from(self.seq.to_string()),
+            Bytes::from(self.ttl_ms.to_string()),
+            self.value.clone(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_ignores_the_envelope() {
+        let kv = KvMsg {
+            seq: 7,
+            ttl_ms: 1500,
+            ..KvMsg::new("porto/temp", "18")
+        };
+        assert_eq!(KvMsg::decode(&kv.encode()), Some(kv.clone()));
+
+        let mut frames = vec![Bytes::from("client")];
+        frames.extend(kv.frames());
+        let msg = ZmqMessage::try_from(frames).expect("frames");
+        assert_eq!(KvMsg::decode(&msg), Some(kv));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert_eq!(KvMsg::decode(&ZmqMessage::from("key")), None);
+        let frames = vec!["key", "seven", "0", "value"]
+            .into_iter()
+            .map(Bytes::from)
+            .collect::<Vec<_>>();
+        let msg = ZmqMessage::try_from(frames).expect("frames");
+        assert_eq!(KvMsg::decode(&msg), None);
+        assert!(KvMsg::new("key", "").is_delete());
+    }
+}
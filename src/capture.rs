@@ -0,0 +1,121 @@
+//! Capture (tap) support shared by the brokers.
+//!
+//! A broker started with `--capture <addr>` republishes every frame it
+//! forwards on a PUB socket bound to that address. Each captured message is
+//! the original message prefixed with a frame saying which way it was going.
+//! The `inspect` mode of the brokers subscribes to the tap and prints them.
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use zeromq::{ZmqMessage, prelude::*};
+
+/// Which way a captured message was flowing through the broker.
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// From the frontend (clients/subscribers side) to the backend.
+    FrontendToBackend,
+    /// From the backend (workers/publishers side) to the frontend.
+    BackendToFrontend,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::FrontendToBackend => "frontend->backend",
+            Direction::BackendToFrontend => "backend->frontend",
+        }
+    }
+}
+
+/// PUB socket on which a broker republishes the traffic it forwards.
+pub struct Tap {
+    sock: zeromq::PubSocket,
+}
+
+impl Tap {
+    /// Binds the tap to `addr`, or returns `None` if no address was given.
+    pub async fn bind(addr: Option<SocketAddr>) -> anyhow::Result<Option<Self>> {
+        let Some(addr) = addr else {
+            return Ok(None);
+        };
+        let mut sock = zeromq::PubSocket::new();
+        sock.bind(format!("tcp://{addr}").as_str()).await?;
+        println!("Capturing traffic on {addr}");
+        Ok(Some(Self { sock }))
+    }
+
+    /// Publishes a copy of `message`, tagged with its direction.
+    pub async fn capture(&mut self, direction: Direction, message: &ZmqMessage) {
+        let mut copy = message.clone();
+        copy.push_front(Bytes::from_static(direction.as_str().as_bytes()));
+        if let Err(e) = self.sock.send(copy).await {
+            eprintln!("Error capturing message: {e}");
+        }
+    }
+}
+
+/// Publishes `message` on the tap, if there is one.
+pub async fn capture(tap: &mut Option<Tap>, direction: Direction, message: &ZmqMessage) {
+    if let Some(tap) = tap {
+        tap.capture(direction, message).await;
+    }
+}
+
+/// Inspector code.
+/// Subscribes to a broker tap and pretty-prints every captured message:
+/// the envelope (identities up to the empty delimiter frame) and then the
+/// payload frames, as UTF-8 when printable and as hex otherwise.
+pub async fn inspect_handler(tap_addr: SocketAddr) -> anyhow::Result<()> {
+    let mut sock = zeromq::SubSocket::new();
+    sock.subscribe("").await?;
+    sock.connect(format!("tcp://{tap_addr}").as_str()).await?;
+
+    let mut stdout = tokio::io::stdout();
+    loop {
+        let frames = match sock.recv().await {
+            Ok(msg) => msg.into_vec(),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                continue;
+            }
+        };
+        let Some((direction, frames)) = frames.split_first() else {
+            continue;
+        };
+        let delimiter = frames.iter().position(|f| f.is_empty());
+
+        let mut out = format!(
+            "--- {} ({} frames)\n",
+            String::from_utf8_lossy(direction),
+            frames.len()
+        );
+        for (i, frame) in frames.iter().enumerate() {
+            let kind = match delimiter {
+                Some(d) if i < d => "identity",
+                Some(d) if i == d => "delimiter",
+                _ => "payload",
+            };
+            out.push_str(&format!(
+                "  [{i}] {kind:<9} {:>5}B {}\n",
+                frame.len(),
+                render(frame)
+            ));
+        }
+        stdout.write_all(out.as_bytes()).await?;
+        stdout.flush().await?;
+    }
+}
+
+/// Shows a frame as text if it is printable UTF-8, and as hex otherwise.
+fn render(frame: &[u8]) -> String {
+    match std::str::from_utf8(frame) {
+        Ok(s) if s.chars().all(|c| !c.is_control() || c == '\n') => format!("{s:?}"),
+        _ => frame
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
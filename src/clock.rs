@@ -0,0 +1,12 @@
+//! Wall-clock time shared by the examples, for timestamps that go over the
+//! wire (deadlines, trace hops, signatures).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
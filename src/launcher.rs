@@ -0,0 +1,120 @@
+//! Entry point when the program is run under its own name.
+//!
+//! Each example is reached through a symlink named after it (see
+//! `examples/`), or with `sdle_class <example> <role> ...`. This module
+//! answers `sdle_class` alone and `sdle_class list` with the examples, their
+//! roles and what they do, and starts `sdle_class run <example>` (see
+//! [`crate::run`]) and `sdle_class topology` (see [`crate::topology`]);
+//! everything else goes to `pluribus!`.
+
+/// An example of the program.
+pub struct Example {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// The arguments of the example, with its roles as subcommands.
+    pub command: fn() -> clap::Command,
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        name: "c00_hello",
+        about: "Request-reply \"Hello\"/\"World\", and Freelance clients talking to several servers.",
+        command: crate::c00_hello::command,
+    },
+    Example {
+        name: "c00_pubsub",
+        about: "A publisher sending updates to the clients subscribed to their topic.",
+        command: crate::c00_pubsub::command,
+    },
+    Example {
+        name: "c01_polling",
+        about: "A client polling two zipcode publishers at the same time.",
+        command: crate::c01_polling::command,
+    },
+    Example {
+        name: "c01_queue",
+        about: "A ROUTER/DEALER broker, with Titanic persistence, Binary Star failover, federation and priorities.",
+        command: crate::c01_queue::command,
+    },
+    Example {
+        name: "c02_xpubxsub",
+        about: "A Pub/Sub broker with a Last Value Cache, broker trees, access control and middleware.",
+        command: crate::c02_xpubxsub::command,
+    },
+    Example {
+        name: "c02_pushpull",
+        about: "A ventilator, workers and a sink sharing out batches of tasks, and MapReduce jobs.",
+        command: crate::c02_pushpull::command,
+    },
+    Example {
+        name: "c03_clone",
+        about: "A key-value store shared with the Clone pattern.",
+        command: crate::c03_clone::command,
+    },
+    Example {
+        name: "broker_stat",
+        about: "Polls the admin endpoint of a broker and shows its counters as a table.",
+        command: crate::broker_stat::command,
+    },
+];
+
+/// Handles the arguments that do not name an example; `None` for the others.
+pub fn handle(argv: &[String]) -> Option<anyhow::Result<()>> {
+    let program = env!("CARGO_PKG_NAME");
+    let name = argv.first().and_then(|arg0| arg0.rsplit('/').next());
+    if name != Some(program) {
+        return None; // Run through a symlink
+    }
+    match argv.get(1).map(String::as_str) {
+        None | Some("help" | "-h" | "--help") => {
+            println!("Usage: {program} <example> <role> [args...]");
+            println!("       {program} list");
+            println!("       {program} run <example> [--workers <n>] [--clients <n>]");
+            println!("       {program} topology <up|check> <file>");
+            println!(
+                "   or: <example> <role> [args...], through a symlink named after the example"
+            );
+            println!();
+            list();
+            Some(Ok(()))
+        }
+        Some("list") => {
+            list();
+            Some(Ok(()))
+        }
+        Some("run") => Some(crate::run::main(&argv[1..])),
+        Some("topology") => Some(crate::topology::main(&argv[1..])),
+        Some(_) => None,
+    }
+}
+
+/// Prints every example, its roles and what it does.
+pub fn list() {
+    let width = EXAMPLES
+        .iter()
+        .map(|example| example.name.len())
+        .max()
+        .unwrap_or(0)
+        + 2;
+    println!("Examples:");
+    for example in EXAMPLES {
+        let roles: Vec<String> = (example.command)()
+            .get_subcommands()
+            .map(|role| role.get_name().to_string())
+            .collect();
+        println!("  {:<width$}{}", example.name, example.about);
+        match roles.is_empty() {
+            true => println!("  {:<width$}(no roles, run it with its arguments)", ""),
+            false => println!("  {:<width$}roles: {}", "", roles.join(", ")),
+        }
+    }
+    println!();
+    println!(
+        "Run `{} <example> --help` for the options of an example.",
+        env!("CARGO_PKG_NAME")
+    );
+    println!(
+        "Run `{} run --help` for the examples that run as a whole.",
+        env!("CARGO_PKG_NAME")
+    );
+}
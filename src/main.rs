@@ -1,16 +1,32 @@
 use pluribus::pluribus;
 
+mod admin;
+mod capture;
+mod clock;
+mod launcher;
+mod output;
+mod run;
+mod topology;
+
 mod c00_hello;
 mod c00_pubsub;
 mod c01_polling;
 mod c01_queue;
 mod c02_xpubxsub;
 mod c02_pushpull;
+mod c03_clone;
+mod broker_stat;
 
 /// The entry point of the program.
-/// Executes one of the examples based on the name of the executable.
+/// Executes one of the examples based on the name of the executable, or on
+/// the first argument when run as `sdle_class <example>`.
 /// Check README.md or the pluribus documentation on how to execute the examples.
+#[allow(clippy::cmp_owned)] // triggered inside the `pluribus!` expansion
 fn main() -> anyhow::Result<()> {
+    let argv: Vec<String> = std::env::args().collect();
+    if let Some(result) = launcher::handle(&argv) {
+        return result;
+    }
     pluribus!(
         symbol: main;
         returns: anyhow::Result<()>;
@@ -21,5 +37,7 @@ fn main() -> anyhow::Result<()> {
         - c01_queue;
         - c02_xpubxsub;
         - c02_pushpull;
-    )(&std::env::args().collect::<Vec<_>>())
+        - c03_clone;
+        - broker_stat;
+    )(&argv)
 }
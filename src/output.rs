@@ -0,0 +1,19 @@
+//! Output of the child processes started by `sdle_class run` and
+//! `sdle_class topology up`.
+//!
+//! Each child writes to pipes, read line by line and printed with the name
+//! of the child in front of each line, so that the output of the roles can
+//! be told apart.
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Prints the lines of a child process named `name`, to stderr if `stderr`.
+pub async fn forward(name: String, stream: impl AsyncRead + Unpin, stderr: bool) {
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match stderr {
+            true => eprintln!("[{name}] {line}"),
+            false => println!("[{name}] {line}"),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! `sdle_class run <example>`: a whole topology from one command.
+//!
+//! Each role of the example is a child process, as if it was started from
+//! its own terminal, on free local ports. Roles that bind start first, and
+//! the others a moment later. Their output is prefixed with their name (see
+//! [`crate::output`]), and once the driving roles (the clients, or the sink)
+//! are done, the others are stopped.
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use clap::{Parser, ValueEnum};
+use tokio::{
+    process::{Child, Command},
+    task::JoinSet,
+    time::{interval, sleep},
+};
+
+use crate::output;
+
+/// Time for the roles of a stage to bind before starting the next stage.
+const STAGE_DELAY: Duration = Duration::from_millis(300);
+/// How often to check whether the roles exited.
+const POLL: Duration = Duration::from_millis(100);
+
+/// The examples with a topology that ends by itself.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Example {
+    /// A server and `--clients` clients, that drive.
+    #[value(name = "c00_hello")]
+    C00Hello,
+    /// A broker, `--workers` workers and `--clients` clients, that drive.
+    #[value(name = "c01_queue")]
+    C01Queue,
+    /// A sink, that drives, a ventilator and `--workers` workers.
+    #[value(name = "c02_pushpull")]
+    C02Pushpull,
+}
+
+/// Run the roles of an example in this process.
+#[derive(clap::Parser)]
+#[command(name = "sdle_class run")]
+struct Cli {
+    #[arg(value_enum)]
+    example: Example,
+    /// Number of workers.
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Number of clients.
+    #[arg(long)]
+    clients: Option<usize>,
+}
+
+/// A free local port, kept by a listener so that nobody else takes it
+/// until the role that binds it starts.
+pub struct Port {
+    listener: TcpListener,
+    addr: SocketAddr,
+}
+
+impl Port {
+    pub fn reserve() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let addr = listener.local_addr()?;
+        Ok(Self { listener, addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Frees the port, for the role about to bind it.
+    pub fn release(self) -> SocketAddr {
+        drop(self.listener);
+        self.addr
+    }
+}
+
+/// A role to start, with the arguments of its example.
+struct Role {
+    name: String,
+    args: Vec<String>,
+    /// Whether the topology is done once it and the other drivers are.
+    drives: bool,
+    /// The ports it binds, released right before it starts.
+    binds: Vec<Port>,
+}
+
+impl Role {
+    fn new(name: impl Into<String>, args: &[&str], drives: bool) -> Self {
+        Self {
+            name: name.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            drives,
+            binds: Vec::new(),
+        }
+    }
+
+    fn binding(mut self, ports: impl IntoIterator<Item = Port>) -> Self {
+        self.binds.extend(ports);
+        self
+    }
+
+    /// `count` roles named `name-1`, `name-2`, ...
+    fn many(count: usize, name: &str, args: &[&str], drives: bool) -> Vec<Self> {
+        (1..=count)
+            .map(|i| Self::new(format!("{name}-{i}"), args, drives))
+            .collect()
+    }
+}
+
+pub fn main(args: &[String]) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(args);
+    let stages = topology(&cli)?;
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(run(cli.example, stages))
+}
+
+/// The roles of the example, in the order they should start.
+fn topology(cli: &Cli) -> anyhow::Result<Vec<Vec<Role>>> {
+    let clients = cli.clients.unwrap_or(1);
+    let workers = cli.workers.unwrap_or(2);
+    Ok(match cli.example {
+        Example::C00Hello => {
+            if cli.workers.is_some() {
+                bail!("c00_hello has a single server and no workers");
+            }
+            let port = Port::reserve()?;
+            let addr = port.addr().to_string();
+            vec![
+                vec![Role::new("server", &["server", &addr], false).binding([port])],
+                Role::many(clients, "client", &["client", &addr], true),
+            ]
+        }
+        Example::C01Queue => {
+            let ports = [Port::reserve()?, Port::reserve()?];
+            let [client_addr, worker_addr] = ports.each_ref().map(|port| port.addr().to_string());
+            vec![
+                vec![
+                    Role::new("broker", &["broker", &client_addr, &worker_addr], false)
+                        .binding(ports),
+                ],
+                Role::many(workers, "worker", &["worker", &worker_addr], false),
+                Role::many(clients, "client", &["client", &client_addr], true),
+            ]
+        }
+        Example::C02Pushpull => {
+            if cli.clients.is_some() {
+                bail!("c02_pushpull has no clients");
+            }
+            let sender_port = Port::reserve()?;
+            let sink_port = Port::reserve()?;
+            let register_port = Port::reserve()?;
+            let control_port = Port::reserve()?;
+            let [sender, sink, register, control] =
+                [&sender_port, &sink_port, &register_port, &control_port]
+                    .map(|port| port.addr().to_string());
+            let min_workers = workers.to_string();
+            vec![
+                vec![
+                    Role::new("sink", &["sink", &sink, "--control", &control], true)
+                        .binding([sink_port, control_port]),
+                ],
+                vec![
+                    Role::new(
+                        "ventilator",
+                        &[
+                            "ventilator",
+                            &sender,
+                            &sink,
+                            &register,
+                            "--min-workers",
+                            &min_workers,
+                            "--control",
+                            &control,
+                        ],
+                        false,
+                    )
+                    .binding([sender_port, register_port]),
+                ],
+                Role::many(
+                    workers,
+                    "worker",
+                    &["worker", &sender, &sink, &register, "--control", &control],
+                    false,
+                ),
+            ]
+        }
+    })
+}
+
+/// A running role.
+struct Process {
+    name: String,
+    drives: bool,
+    child: Child,
+    status: Option<ExitStatus>,
+}
+
+/// Starts the stages one after the other, and waits for the drivers.
+async fn run(example: Example, stages: Vec<Vec<Role>>) -> anyhow::Result<()> {
+    let mut processes = Vec::new();
+    let mut logs = JoinSet::new();
+    let started = tokio::select! {
+        started = start(example, stages, &mut processes, &mut logs) => started,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    let result = match started {
+        Ok(()) => wait(&mut processes).await,
+        Err(e) => Err(e),
+    };
+
+    for process in processes.iter_mut().rev() {
+        if process.status.is_none() {
+            _ = process.child.start_kill();
+            _ = process.child.wait().await;
+        }
+    }
+    logs.join_all().await;
+    result
+}
+
+/// Starts the roles of each stage, a moment after the stage before it.
+async fn start(
+    example: Example,
+    stages: Vec<Vec<Role>>,
+    processes: &mut Vec<Process>,
+    logs: &mut JoinSet<()>,
+) -> anyhow::Result<()> {
+    let program = std::env::current_exe()?;
+    let example = example.to_possible_value().expect("not skipped");
+    for (i, stage) in stages.into_iter().enumerate() {
+        if i > 0 {
+            sleep(STAGE_DELAY).await;
+        }
+        for role in stage {
+            for port in role.binds {
+                port.release();
+            }
+            let mut child = Command::new(&program)
+                .arg(example.get_name())
+                .args(&role.args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("starting {}", role.name))?;
+            if let Some(stdout) = child.stdout.take() {
+                logs.spawn(output::forward(role.name.clone(), stdout, false));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                logs.spawn(output::forward(role.name.clone(), stderr, true));
+            }
+            processes.push(Process {
+                name: role.name,
+                drives: role.drives,
+                child,
+                status: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the drivers to exit, or for a role to fail or Ctrl-C.
+async fn wait(processes: &mut [Process]) -> anyhow::Result<()> {
+    let mut poll = interval(POLL);
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+        for process in processes
+            .iter_mut()
+            .filter(|process| process.status.is_none())
+        {
+            // The others may exit before the drivers, but not fail
+            if let Some(status) = process.child.try_wait()? {
+                process.status = Some(status);
+                if !status.success() {
+                    bail!("{} failed: it exited with {status}", process.name);
+                }
+            }
+        }
+        let mut drivers = processes.iter().filter(|process| process.drives);
+        if drivers.all(|process| process.status.is_some()) {
+            return Ok(());
+        }
+    }
+}
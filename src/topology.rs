@@ -0,0 +1,389 @@
+//! `sdle_class topology up <file>`: a deployment described in a TOML file.
+//!
+//! The file lists named nodes, each one an example started with a role:
+//!
+//! ```toml
+//! [nodes.sink]
+//! example = "c02_pushpull"
+//! role = "sink"
+//! endpoints = { results = "127.0.0.1:9877", control = "127.0.0.1:9895" }
+//! args = ["{results}", "--control", "{control}"]
+//!
+//! [nodes.worker]
+//! example = "c02_pushpull"
+//! role = "worker"
+//! replicas = 2
+//! args = ["{ventilator.tasks}", "{sink.results}", "{ventilator.register}", "--control", "{sink.control}"]
+//! ```
+//!
+//! `endpoints` are the addresses a node binds, that its `args` refer to as
+//! `{name}` and the other nodes as `{node.name}`. A node starts after the
+//! nodes whose endpoints it uses and those in its `depends_on`, once they are
+//! healthy: their endpoints accept connections, or, without endpoints, they
+//! are still running after a moment. Each node (and each of its `replicas`)
+//! is a child process, whose output is printed with its name in front of it.
+//! Ctrl-C stops them all, the last started first.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use clap::Parser;
+use serde::Deserialize;
+use tokio::{
+    net::TcpStream,
+    process::{Child, Command},
+    task::JoinSet,
+    time::{Instant, interval, sleep},
+};
+
+use crate::{launcher::EXAMPLES, output};
+
+/// Time for the endpoints of a node to accept connections.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a node without endpoints must run to be considered healthy.
+const GRACE: Duration = Duration::from_millis(500);
+/// How often to check the endpoints, and whether the nodes exited.
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(clap::Parser)]
+#[command(name = "sdle_class topology")]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Mode,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Mode {
+    /// Start the nodes of a topology file, until Ctrl-C.
+    Up { file: PathBuf },
+    /// Check a topology file and print the commands of its nodes, in order.
+    Check { file: PathBuf },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Topology {
+    nodes: BTreeMap<String, Node>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Node {
+    example: String,
+    role: Option<String>,
+    /// Addresses bound by the node, by name.
+    #[serde(default)]
+    endpoints: BTreeMap<String, SocketAddr>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "one")]
+    replicas: usize,
+    #[serde(default)]
+    depends_on: Vec<String>,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// A node ready to start, with its placeholders replaced.
+struct Plan {
+    name: String,
+    endpoints: Vec<SocketAddr>,
+    /// Arguments of the program, starting with the example.
+    args: Vec<String>,
+    replicas: usize,
+}
+
+impl Plan {
+    fn replica_names(&self) -> Vec<String> {
+        match self.replicas {
+            1 => vec![self.name.clone()],
+            n => (1..=n).map(|i| format!("{}-{i}", self.name)).collect(),
+        }
+    }
+}
+
+/// A running child process.
+struct Process {
+    name: String,
+    child: Child,
+    status: Option<ExitStatus>,
+}
+
+pub fn main(args: &[String]) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(args);
+    match cli.cmd {
+        Mode::Up { file } => {
+            let plans = load(&file)?;
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+            rt.block_on(up(plans))
+        }
+        Mode::Check { file } => {
+            for plan in load(&file)? {
+                let replicas = match plan.replicas {
+                    1 => String::new(),
+                    n => format!(" (x{n})"),
+                };
+                println!("{}{replicas}: {}", plan.name, plan.args.join(" "));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Reads and checks a topology file, and sorts its nodes in start order.
+fn load(path: &Path) -> anyhow::Result<Vec<Plan>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading topology {}", path.display()))?;
+    let topology: Topology =
+        toml::from_str(&text).with_context(|| format!("parsing topology {}", path.display()))?;
+
+    let mut plans = BTreeMap::new();
+    let mut dependencies: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (name, node) in &topology.nodes {
+        let plan = plan(
+            name,
+            node,
+            &topology.nodes,
+            dependencies.entry(name).or_default(),
+        )
+        .with_context(|| format!("node {name}"))?;
+        plans.insert(name.as_str(), plan);
+    }
+
+    // Start the nodes whose dependencies all started, until none is left
+    let mut order = Vec::new();
+    while !dependencies.is_empty() {
+        let ready: Vec<&str> = dependencies
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+        if ready.is_empty() {
+            let names: Vec<&str> = dependencies.keys().copied().collect();
+            bail!("dependency cycle between {}", names.join(", "));
+        }
+        for name in ready {
+            dependencies.remove(name);
+            for deps in dependencies.values_mut() {
+                deps.remove(name);
+            }
+            order.extend(plans.remove(name));
+        }
+    }
+    Ok(order)
+}
+
+/// Checks a node and replaces the placeholders of its arguments.
+fn plan<'a>(
+    name: &str,
+    node: &Node,
+    nodes: &'a BTreeMap<String, Node>,
+    dependencies: &mut BTreeSet<&'a str>,
+) -> anyhow::Result<Plan> {
+    let Some(example) = EXAMPLES.iter().find(|example| example.name == node.example) else {
+        bail!("no example {:?}", node.example);
+    };
+    let roles: Vec<String> = (example.command)()
+        .get_subcommands()
+        .map(|role| role.get_name().to_string())
+        .collect();
+    match &node.role {
+        Some(role) if !roles.contains(role) => {
+            bail!(
+                "{} has no role {role:?}, only {}",
+                example.name,
+                roles.join(", ")
+            )
+        }
+        None if !roles.is_empty() => bail!("missing role, one of {}", roles.join(", ")),
+        _ => {}
+    }
+    if node.replicas == 0 {
+        bail!("replicas must be at least 1");
+    }
+    if node.replicas > 1 && !node.endpoints.is_empty() {
+        bail!("replicas of a node with endpoints would bind the same addresses");
+    }
+
+    for dependency in &node.depends_on {
+        let Some((dependency, _)) = nodes.get_key_value(dependency) else {
+            bail!("depends on unknown node {dependency:?}");
+        };
+        dependencies.insert(dependency);
+    }
+    let mut args = vec![node.example.clone()];
+    args.extend(node.role.clone());
+    for arg in &node.args {
+        args.push(substitute(arg, |placeholder| {
+            match placeholder.split_once('.') {
+                None => node.endpoints.get(placeholder).copied(),
+                Some((other, endpoint)) => {
+                    let (other, node) = nodes.get_key_value(other)?;
+                    let addr = node.endpoints.get(endpoint).copied()?;
+                    if other != name {
+                        dependencies.insert(other);
+                    }
+                    Some(addr)
+                }
+            }
+        })?);
+    }
+
+    Ok(Plan {
+        name: name.to_string(),
+        endpoints: node.endpoints.values().copied().collect(),
+        args,
+        replicas: node.replicas,
+    })
+}
+
+/// Replaces each `{placeholder}` of `arg` with its address.
+fn substitute(
+    arg: &str,
+    mut lookup: impl FnMut(&str) -> Option<SocketAddr>,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("unclosed placeholder in {arg:?}");
+        };
+        let placeholder = &rest[start + 1..start + end];
+        let Some(addr) = lookup(placeholder) else {
+            bail!("unknown endpoint {{{placeholder}}}");
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&addr.to_string());
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Starts the nodes in order, and stops them on Ctrl-C.
+async fn up(plans: Vec<Plan>) -> anyhow::Result<()> {
+    let mut processes = Vec::new();
+    let mut logs = JoinSet::new();
+    let started = tokio::select! {
+        started = start(&plans, &mut processes, &mut logs) => started,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    if started.is_ok() {
+        println!("All nodes are up, Ctrl-C to stop them");
+        watch(&mut processes).await;
+    }
+
+    for process in processes.iter_mut().rev() {
+        if process.status.is_none() {
+            println!("Stopping {}", process.name);
+            _ = process.child.start_kill();
+            _ = process.child.wait().await;
+        }
+    }
+    logs.join_all().await;
+    started
+}
+
+/// Starts the replicas of each node, after the nodes before it are healthy.
+async fn start(
+    plans: &[Plan],
+    processes: &mut Vec<Process>,
+    logs: &mut JoinSet<()>,
+) -> anyhow::Result<()> {
+    let program = std::env::current_exe()?;
+    for plan in plans {
+        let first = processes.len();
+        for name in plan.replica_names() {
+            println!("Starting {name}: {}", plan.args.join(" "));
+            let mut child = Command::new(&program)
+                .args(&plan.args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("starting {name}"))?;
+            if let Some(stdout) = child.stdout.take() {
+                logs.spawn(output::forward(name.clone(), stdout, false));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                logs.spawn(output::forward(name.clone(), stderr, true));
+            }
+            processes.push(Process {
+                name,
+                child,
+                status: None,
+            });
+        }
+        healthy(plan, &mut processes[first..]).await?;
+    }
+    Ok(())
+}
+
+/// Waits for the endpoints of a node to accept connections, or, without
+/// endpoints, for its replicas to run for a moment.
+async fn healthy(plan: &Plan, replicas: &mut [Process]) -> anyhow::Result<()> {
+    let deadline = Instant::now() + HEALTH_TIMEOUT;
+    let mut pending = plan.endpoints.clone();
+    let grace = Instant::now() + GRACE;
+    loop {
+        for replica in replicas.iter_mut() {
+            if let Some(status) = replica.child.try_wait()? {
+                replica.status = Some(status);
+                if !status.success() {
+                    bail!("{} exited with {status} while starting", replica.name);
+                }
+            }
+        }
+        let mut still_pending = Vec::new();
+        for addr in pending {
+            if TcpStream::connect(addr).await.is_err() {
+                still_pending.push(addr);
+            }
+        }
+        pending = still_pending;
+        if pending.is_empty() && (!plan.endpoints.is_empty() || Instant::now() >= grace) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            let pending: Vec<String> = pending.iter().map(SocketAddr::to_string).collect();
+            bail!("{} is not listening on {}", plan.name, pending.join(", "));
+        }
+        sleep(POLL).await;
+    }
+}
+
+/// Reports the nodes that exit, until Ctrl-C or until they all did.
+async fn watch(processes: &mut [Process]) {
+    let mut poll = interval(POLL);
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {}
+            _ = tokio::signal::ctrl_c() => return,
+        }
+        for process in processes
+            .iter_mut()
+            .filter(|process| process.status.is_none())
+        {
+            if let Ok(Some(status)) = process.child.try_wait() {
+                println!("{} exited with {status}", process.name);
+                process.status = Some(status);
+            }
+        }
+        if processes.iter().all(|process| process.status.is_some()) {
+            println!("All nodes exited");
+            return;
+        }
+    }
+}